@@ -0,0 +1,285 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand};
+use d4ft4::{D4FTError, FileListItem, Progress, ProgressSink};
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::fs::File;
+
+/// The environment variable checked for the password before prompting for it, for running without a terminal.
+const PASSWORD_VAR: &str = "D4FT4_PASSWORD";
+
+/// Send and receive text and files with d4ft4.
+#[derive(Parser, Debug)]
+#[command(name = "d4ft4", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send text or files to the other end.
+    #[command(subcommand)]
+    Send(SendCommand),
+    /// Receive text or files from the other end.
+    #[command(subcommand)]
+    Receive(ReceiveCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum SendCommand {
+    /// Send a piece of text.
+    Text {
+        text: String,
+        #[command(flatten)]
+        peer: PeerArgs,
+    },
+    /// Send files, without their directory structure.
+    Files {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        #[command(flatten)]
+        peer: PeerArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ReceiveCommand {
+    /// Receive a piece of text and print it.
+    Text {
+        #[command(flatten)]
+        peer: PeerArgs,
+    },
+    /// Receive files into a directory.
+    Files {
+        /// The directory to save the files in.
+        #[arg(short, long, default_value = ".")]
+        out_dir: PathBuf,
+        /// Accept every file without asking.
+        #[arg(short, long)]
+        yes: bool,
+        #[command(flatten)]
+        peer: PeerArgs,
+    },
+}
+
+/// Where to find the other end. One end listens and the other connects.
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+struct PeerArgs {
+    /// Wait for the other end to connect on this address, like `0.0.0.0:2581`.
+    #[arg(short, long, value_name = "ADDRESS")]
+    listen: Option<String>,
+    /// Connect to the other end at this address, like `192.168.1.2:2581`.
+    #[arg(short, long, value_name = "ADDRESS")]
+    connect: Option<String>,
+}
+
+impl PeerArgs {
+    /// Whether to listen, and the address to listen or connect on.
+    fn into_setup(self) -> (bool, String) {
+        match (self.listen, self.connect) {
+            (Some(address), _) => (true, address),
+            (None, Some(address)) => (false, address),
+            (None, None) => unreachable!("clap requires one of --listen or --connect"),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli.command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", error_chain(err.as_ref()));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Send(SendCommand::Text { text, peer }) => {
+            let (listen, address) = peer.into_setup();
+            let mut sender = d4ft4::init_send(listen, address, password()?).await?;
+            sender.send_text(text).await?;
+            sender.close().await?;
+        }
+        Command::Send(SendCommand::Files { paths, peer }) => {
+            let mut handles = Vec::with_capacity(paths.len());
+            for path in &paths {
+                let handle = File::open(path)
+                    .await
+                    .map_err(|source| D4FTError::FileOpenError { source })?;
+                handles.push(handle);
+            }
+
+            let (listen, address) = peer.into_setup();
+            let mut sender = d4ft4::init_send(listen, address, password()?).await?;
+            let bar = progress_bar();
+            sender.set_progress_sink(Some(progress_sink(bar.clone())));
+            sender
+                .send_flat_files(paths.into_iter().zip(handles.iter_mut()).collect())
+                .await?;
+            bar.finish();
+            sender.close().await?;
+        }
+        Command::Receive(ReceiveCommand::Text { peer }) => {
+            let (listen, address) = peer.into_setup();
+            let mut receiver = d4ft4::init_receive(listen, address, password()?).await?;
+            println!("{}", receiver.receive_text().await?);
+            receiver.close().await?;
+        }
+        Command::Receive(ReceiveCommand::Files { out_dir, yes, peer }) => {
+            let (listen, address) = peer.into_setup();
+            let mut receiver = d4ft4::init_receive(listen, address, password()?).await?;
+
+            let files = receiver.receive_file_list().await?;
+            for item in &files {
+                if let FileListItem::File { path, size } = item {
+                    eprintln!("{} ({})", path.display(), indicatif::HumanBytes(*size));
+                }
+            }
+            // Declining still goes through with an empty allowlist, so the sender hears that nothing was accepted
+            let allowlist = if yes || confirm("Accept these files?")? {
+                files
+                    .iter()
+                    .filter(|item| matches!(item, FileListItem::File { .. }))
+                    .map(|item| item.path().to_path_buf())
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            let bar = progress_bar();
+            receiver.set_progress_sink(Some(progress_sink(bar.clone())));
+            receiver
+                .receive_flat_files_fs(allowlist, Some(&out_dir))
+                .await?;
+            bar.finish();
+            receiver.close().await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the password from the environment, or prompt for it without echoing it.
+fn password() -> std::io::Result<String> {
+    match std::env::var(PASSWORD_VAR) {
+        Ok(password) => Ok(password),
+        Err(_) => rpassword::prompt_password("Password: "),
+    }
+}
+
+/// Ask a yes or no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> std::io::Result<bool> {
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn progress_bar() -> ProgressBar {
+    ProgressBar::new(0).with_style(
+        ProgressStyle::with_template(
+            "{msg} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        )
+        .expect("Progress template should be valid")
+        .progress_chars("=> "),
+    )
+}
+
+/// Creates a progress sink that draws transfer progress on the progress bar.
+fn progress_sink(bar: ProgressBar) -> ProgressSink {
+    Box::new(move |progress: &Progress| {
+        bar.set_length(progress.total_size);
+        bar.set_position(progress.total_bytes);
+        bar.set_message(progress.path.display().to_string());
+    })
+}
+
+/// Formats an error along with all of its sources, since d4ft4's errors keep the details in their source.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(&format!(": {err}"));
+        source = err.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Result<Command, clap::Error> {
+        Cli::try_parse_from(std::iter::once("d4ft4").chain(args.iter().copied()))
+            .map(|cli| cli.command)
+    }
+
+    #[test]
+    fn valid_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn send_text_listening() {
+        let command = parse(&["send", "text", "hello", "--listen", "0.0.0.0:2581"]).unwrap();
+        let Command::Send(SendCommand::Text { text, peer }) = command else {
+            panic!("parsed as {command:?}");
+        };
+        assert_eq!(text, "hello");
+        assert_eq!(peer.into_setup(), (true, "0.0.0.0:2581".to_string()));
+    }
+
+    #[test]
+    fn receive_files_defaults() {
+        let command = parse(&["receive", "files", "-c", "192.168.1.2:2581"]).unwrap();
+        let Command::Receive(ReceiveCommand::Files { out_dir, yes, peer }) = command else {
+            panic!("parsed as {command:?}");
+        };
+        assert_eq!(out_dir, PathBuf::from("."));
+        assert!(!yes);
+        assert_eq!(peer.into_setup(), (false, "192.168.1.2:2581".to_string()));
+    }
+
+    #[test]
+    fn send_files_needs_paths() {
+        let err = parse(&["send", "files", "--connect", "127.0.0.1:2581"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn needs_exactly_one_of_listen_or_connect() {
+        let err = parse(&["receive", "text"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+
+        let err = parse(&[
+            "receive",
+            "text",
+            "-l",
+            "0.0.0.0:2581",
+            "-c",
+            "127.0.0.1:2581",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn error_chain_includes_sources() {
+        let err = D4FTError::FileOpenError {
+            source: std::io::Error::new(std::io::ErrorKind::NotFound, "no such file"),
+        };
+        assert_eq!(error_chain(&err), "error opening file: no such file");
+    }
+}
@@ -1,10 +1,12 @@
 use std::ffi::OsStr;
+use std::future::Future;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::{fmt::Debug, io::Read};
 
 use d4ft4::{Connection, D4FTError, D4FTResult};
-use futures::{future, FutureExt, TryFutureExt};
-use tauri::async_runtime::{channel, Mutex, Receiver, Sender};
+use futures::{future, FutureExt, StreamExt, TryFutureExt};
+use tauri::async_runtime::{channel, JoinHandle, Mutex, Receiver, Sender};
 use tauri::Manager;
 use tauri_plugin_dialog::{DialogExt, FileResponse};
 use tokio::fs::File;
@@ -29,9 +31,13 @@ pub fn run() {
 struct State {
     sender: Mutex<Option<d4ft4::Sender>>,
     receiver: Mutex<Option<d4ft4::Receiver>>,
+    /// Kept apart from the connections, which stay locked while a transfer is running.
+    sender_cancel: std::sync::Mutex<Option<d4ft4::CancelToken>>,
+    receiver_cancel: std::sync::Mutex<Option<d4ft4::CancelToken>>,
     response_tx: Sender<Message<Response>>,
     response_rx: Mutex<Receiver<Message<Response>>>,
     files: Mutex<Vec<LoadedFile>>,
+    browser: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl State {
@@ -40,11 +46,30 @@ impl State {
         Self {
             sender: Mutex::new(None),
             receiver: Mutex::new(None),
+            sender_cancel: std::sync::Mutex::new(None),
+            receiver_cancel: std::sync::Mutex::new(None),
             response_tx: tx,
             response_rx: Mutex::new(rx),
             files: Mutex::new(Vec::new()),
+            browser: Mutex::new(None),
         }
     }
+
+    /// The cancel token of the connection taking `role`, so that each page only cancels its own connection.
+    fn cancel_token(&self, role: d4ft4::PeerRole) -> &std::sync::Mutex<Option<d4ft4::CancelToken>> {
+        match role {
+            d4ft4::PeerRole::Sender => &self.sender_cancel,
+            d4ft4::PeerRole::Receiver => &self.receiver_cancel,
+        }
+    }
+
+    /// Remembers the cancel token of a newly set up connection, replacing that of the one it took over from.
+    fn track_cancel(&self, role: d4ft4::PeerRole, token: d4ft4::CancelToken) {
+        *self
+            .cancel_token(role)
+            .lock()
+            .expect("Cancel lock should not be poisoned") = Some(token);
+    }
 }
 
 #[derive(Debug)]
@@ -110,6 +135,11 @@ enum Call {
         allowlist: Vec<String>,
         out_dir: Option<String>,
     },
+    BrowsePeers,
+    StopBrowsing,
+    Cancel {
+        role: d4ft4::PeerRole,
+    },
     // SendFile { conn_id: usize, path: String },
     // ReceiveFile { conn_id: usize, path: String },
 }
@@ -125,6 +155,7 @@ struct SetupParams {
 #[derive(Debug, serde::Serialize)]
 #[serde(tag = "name", content = "content")]
 enum Response {
+    Listening(Vec<SocketAddr>),
     SetupComplete,
     TextSent,
     TextReceived(String),
@@ -132,6 +163,8 @@ enum Response {
     FilesSent,
     ReceivedFileList(Vec<d4ft4::FileListItem>),
     ReceivedFiles,
+    Progress(d4ft4::Progress),
+    PeerFound(d4ft4::DiscoveredPeer),
     Error(String),
 }
 
@@ -156,12 +189,27 @@ async fn handle_message(
             address,
             is_server,
             password,
-        }) => Some(match d4ft4::init_send(is_server, address, password).await {
-            Ok(sender) => {
-                *state.sender.lock().await = Some(sender);
-                dbg!(Response::SetupComplete)
+        }) => Some({
+            let sender = if is_server {
+                listen(
+                    &state,
+                    &call.return_path,
+                    &address,
+                    d4ft4::PeerRole::Sender,
+                    |listener| listener.accept_send(password, Default::default()),
+                )
+                .await
+            } else {
+                d4ft4::init_send(false, address, password).await
+            };
+            match sender {
+                Ok(sender) => {
+                    state.track_cancel(d4ft4::PeerRole::Sender, sender.cancel_token());
+                    *state.sender.lock().await = Some(sender);
+                    dbg!(Response::SetupComplete)
+                }
+                Err(err) => dbg!(Response::Error(format!("{err:?}"))),
             }
-            Err(err) => dbg!(Response::Error(format!("{err:?}"))),
         }),
         Call::SetupReceiver(SetupParams {
             address,
@@ -172,8 +220,21 @@ async fn handle_message(
             // drop the existing receiver so we don't get "address already in use"
             // TODO: Figure out how to do this if the sender was listening before
             *receiver_lock = None;
-            match d4ft4::init_receive(is_server, address, password).await {
+            let receiver = if is_server {
+                listen(
+                    &state,
+                    &call.return_path,
+                    &address,
+                    d4ft4::PeerRole::Receiver,
+                    |listener| listener.accept_receive(password, Default::default()),
+                )
+                .await
+            } else {
+                d4ft4::init_receive(false, address, password).await
+            };
+            match receiver {
                 Ok(receiver) => {
+                    state.track_cancel(d4ft4::PeerRole::Receiver, receiver.cancel_token());
                     *receiver_lock = Some(receiver);
                     Response::SetupComplete
                 }
@@ -321,16 +382,23 @@ async fn handle_message(
                     }),
             )
             .await;
-            match (sending_files, state.sender.lock().await.as_mut()) {
-                (Ok(files), Some(sender)) => sender
-                    .send_flat_files(files)
+            match sending_files {
+                Ok(files) => {
+                    let sink = progress_sink(&state, call.return_path.clone());
+                    with_locked_conn(&state.sender, |sender| {
+                        async move {
+                            sender.set_progress_sink(Some(sink));
+                            sender
+                                .send_flat_files(files)
+                                .await
+                                .map(|_| Response::FilesSent)
+                        }
+                        .boxed()
+                    })
                     .await
-                    .map(|_| Response::FilesSent)
-                    .map_err(|err| format!("{err:?}")),
-                (Ok(_), None) => Err("connection not initialized".to_string()),
-                (Err(_), _) => Err("could not open file".to_string()),
+                }
+                Err(_) => Response::Error("could not open file".to_string()),
             }
-            .into()
         }),
         Call::ReceiveFileList => Some(
             with_locked_conn(&state.receiver, |receiver| {
@@ -347,8 +415,10 @@ async fn handle_message(
                 .map(|name| PathBuf::from(name))
                 .collect::<Vec<_>>();
 
+            let sink = progress_sink(&state, call.return_path.clone());
             with_locked_conn(&state.receiver, |receiver| {
                 async move {
+                    receiver.set_progress_sink(Some(sink));
                     receiver
                         .receive_flat_files_fs(allowlist, out_dir.as_ref().map(AsRef::as_ref))
                         .await
@@ -358,6 +428,56 @@ async fn handle_message(
             })
             .await
         }),
+        Call::BrowsePeers => {
+            let mut browser = state.browser.lock().await;
+            if let Some(task) = browser.take() {
+                task.abort();
+            }
+
+            match d4ft4::Discovery::new().browse().await {
+                Ok(peers) => {
+                    let response_tx = state.response_tx.clone();
+                    let return_path = call.return_path.clone();
+                    *browser = Some(tauri::async_runtime::spawn(async move {
+                        let mut peers = Box::pin(peers);
+                        while let Some(peer) = peers.next().await {
+                            let (message, failed) = match peer {
+                                Ok(peer) => (Response::PeerFound(peer), false),
+                                Err(err) => (Response::Error(format!("{err:?}")), true),
+                            };
+                            let sent = response_tx
+                                .send(Message {
+                                    return_path: return_path.clone(),
+                                    message,
+                                })
+                                .await;
+                            if failed || sent.is_err() {
+                                break;
+                            }
+                        }
+                    }));
+                    None
+                }
+                Err(err) => Some(Response::Error(format!("{err:?}"))),
+            }
+        }
+        Call::StopBrowsing => {
+            if let Some(task) = state.browser.lock().await.take() {
+                task.abort();
+            }
+            None
+        }
+        Call::Cancel { role } => {
+            if let Some(token) = state
+                .cancel_token(role)
+                .lock()
+                .expect("Cancel lock should not be poisoned")
+                .as_ref()
+            {
+                token.cancel("cancelled by the user");
+            }
+            None
+        }
     };
 
     if let Some(response) = message {
@@ -385,10 +505,59 @@ where
         return Response::Error("connection not initialized".to_string());
     };
 
-    op(conn_handle)
-        .await
-        .map_err(|err| format!("{err:?}"))
-        .into()
+    let result = op(conn_handle).await;
+    if let Err(D4FTError::Cancelled { .. }) = result {
+        // A cancelled connection can't be used again, and dropping it tells the other end straight away
+        *conn_lock = None;
+    }
+    result.map_err(|err| format!("{err:?}")).into()
+}
+
+/// Binds to `address`, tells the frontend which addresses were bound, and advertises this device on the local network
+/// until `accept` is done, so that nearby peers can find it. The advertised port is the one actually bound, so listening
+/// on port 0 works too. Discovery is best-effort, so setup goes ahead without it if the beacon can't be sent.
+async fn listen<Conn, F, Fut>(
+    state: &State,
+    return_path: &[String],
+    address: &str,
+    role: d4ft4::PeerRole,
+    accept: F,
+) -> D4FTResult<Conn>
+where
+    F: FnOnce(d4ft4::Listener) -> Fut,
+    Fut: Future<Output = D4FTResult<Conn>>,
+{
+    let listener = d4ft4::Listener::bind(address).await?;
+    let addresses = listener.local_addrs()?;
+    // The frontend only needs this to show the user where to connect, so carry on if it has gone away
+    let _ = state
+        .response_tx
+        .send(Message {
+            return_path: return_path.to_vec(),
+            message: Response::Listening(addresses.clone()),
+        })
+        .await;
+    let port = addresses.first().map(SocketAddr::port);
+    let _advertisement = match port {
+        Some(port) => d4ft4::Discovery::new()
+            .advertise(tauri_plugin_os::hostname(), role, port)
+            .await
+            .ok(),
+        None => None,
+    };
+    accept(listener).await
+}
+
+/// Creates a progress sink that forwards progress updates to the frontend as responses.
+fn progress_sink(state: &State, return_path: Vec<String>) -> d4ft4::ProgressSink {
+    let response_tx = state.response_tx.clone();
+    Box::new(move |progress| {
+        // Progress updates are best-effort, so drop them instead of blocking if the frontend falls behind
+        let _ = response_tx.try_send(Message {
+            return_path: return_path.clone(),
+            message: Response::Progress(progress.clone()),
+        });
+    })
 }
 
 #[tauri::command]
@@ -1,9 +1,12 @@
-module Components exposing (ErrorMessage, ErrorMessageQueue, ToolbarArgs, addErrorMessage, initErrorMessageQueue, markErrorsRead, viewToolbar)
+module Components exposing (ErrorMessage, ErrorMessageQueue, ToolbarArgs, addErrorMessage, initErrorMessageQueue, markErrorsRead, viewListening, viewToolbar, viewTransferProgress)
 
+import Filesize
 import Html exposing (..)
+import Html.Attributes as Attributes
 import Material.Icons as Filled
 import Material.Icons.Types exposing (Coloring(..))
 import Maybe.Extra as Maybe
+import Messaging exposing (TransferProgress)
 import Theme
 import W.Badge as Badge
 import W.Button as Button
@@ -138,3 +141,34 @@ viewErrorMessage error =
     Message.view
         [ Message.danger, Message.footer [ text error.message ] ]
         [ text <| String.join " / " error.source ]
+
+
+viewListening : List String -> Html msg
+viewListening addresses =
+    if List.isEmpty addresses then
+        text ""
+
+    else
+        text <| "Listening on " ++ String.join ", " addresses
+
+
+viewTransferProgress : msg -> Maybe TransferProgress -> Html msg
+viewTransferProgress onCancel maybeProgress =
+    case maybeProgress of
+        Just current ->
+            Container.view
+                [ Container.vertical
+                , Container.gap_1
+                ]
+                [ text <| current.path ++ " (" ++ Filesize.format (round current.bytesPerSecond) ++ "/s)"
+                , Html.progress
+                    [ Attributes.max <| String.fromInt current.totalSize
+                    , Attributes.value <| String.fromInt current.totalBytes
+                    , Attributes.style "width" "100%"
+                    ]
+                    []
+                , Button.view [ Button.danger ] { label = [ text "Cancel" ], onClick = onCancel }
+                ]
+
+        Nothing ->
+            text ""
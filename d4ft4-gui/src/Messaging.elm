@@ -1,4 +1,4 @@
-port module Messaging exposing (Call(..), Message, Response(..), callBackend, filesInList, receiveBackendMessage)
+port module Messaging exposing (Call(..), Message, NearbyPeer, Response(..), TransferProgress, callBackend, filesInList, receiveBackendMessage)
 
 import Json.Decode as Decode exposing (Decoder)
 import Json.Encode as Encode exposing (Value)
@@ -40,6 +40,9 @@ type Call
     | SendFiles { names : List String }
     | ReceiveFileList
     | ReceiveFiles { allowlist : List String, outDir : Maybe String }
+    | BrowsePeers
+    | StopBrowsing
+    | Cancel { role : String }
 
 
 type alias SetupParams =
@@ -50,16 +53,36 @@ type alias SetupParams =
 
 
 type Response
-    = SetupComplete
+    = Listening (List String)
+    | SetupComplete
     | TextSent
     | TextReceived String
     | FileSelected String
     | FilesSent
     | ReceivedFileList (List FileListItem)
     | ReceivedFiles
+    | Progress TransferProgress
+    | PeerFound NearbyPeer
     | Error String
 
 
+type alias TransferProgress =
+    { path : String
+    , fileBytes : Int
+    , fileSize : Int
+    , totalBytes : Int
+    , totalSize : Int
+    , bytesPerSecond : Float
+    }
+
+
+type alias NearbyPeer =
+    { name : String
+    , role : String
+    , address : String
+    }
+
+
 type FileListItem
     = File { path : String, size : Int }
     | Directory { path : String }
@@ -138,6 +161,19 @@ encodeCall call =
                                 ]
                           )
                         ]
+
+                    BrowsePeers ->
+                        [ ( "name", Encode.string "BrowsePeers" ) ]
+
+                    StopBrowsing ->
+                        [ ( "name", Encode.string "StopBrowsing" ) ]
+
+                    Cancel { role } ->
+                        [ ( "name", Encode.string "Cancel" )
+                        , ( "args"
+                          , Encode.object [ ( "role", Encode.string role ) ]
+                          )
+                        ]
                 )
           )
         ]
@@ -161,6 +197,9 @@ decodeResponse =
                 |> Decode.andThen
                     (\messageName ->
                         case messageName of
+                            "Listening" ->
+                                Decode.field "content" <| Decode.map Listening <| Decode.list Decode.string
+
                             "SetupComplete" ->
                                 Decode.succeed SetupComplete
 
@@ -182,6 +221,12 @@ decodeResponse =
                             "ReceivedFiles" ->
                                 Decode.succeed ReceivedFiles
 
+                            "Progress" ->
+                                Decode.field "content" <| Decode.map Progress decodeTransferProgress
+
+                            "PeerFound" ->
+                                Decode.field "content" <| Decode.map PeerFound decodeNearbyPeer
+
                             "Error" ->
                                 Decode.field "content" <| Decode.map Error Decode.string
 
@@ -192,6 +237,25 @@ decodeResponse =
         )
 
 
+decodeTransferProgress : Decoder TransferProgress
+decodeTransferProgress =
+    Decode.map6 TransferProgress
+        (Decode.field "path" Decode.string)
+        (Decode.field "file-bytes" Decode.int)
+        (Decode.field "file-size" Decode.int)
+        (Decode.field "total-bytes" Decode.int)
+        (Decode.field "total-size" Decode.int)
+        (Decode.field "bytes-per-second" Decode.float)
+
+
+decodeNearbyPeer : Decoder NearbyPeer
+decodeNearbyPeer =
+    Decode.map3 NearbyPeer
+        (Decode.field "name" Decode.string)
+        (Decode.field "role" Decode.string)
+        (Decode.field "address" Decode.string)
+
+
 decodeFileListItem : Decoder FileListItem
 decodeFileListItem =
     Decode.field "type" Decode.string
@@ -1,10 +1,11 @@
-module Peer exposing (Mode(..), Model, Msg(..), addressString, init, statusString, update, view)
+module Peer exposing (Mode(..), Model, Msg(..), addressString, browseCmd, init, statusString, update, view)
 
 import Browser.Dom
 import Html exposing (..)
 import Html.Attributes
 import Material.Icons exposing (mode)
 import Maybe.Extra
+import Messaging exposing (NearbyPeer)
 import Task
 import Theme
 import W.Button as Button
@@ -50,6 +51,7 @@ type alias Model =
     , mode : Mode
     , address : String
     , portNum : InputInt.Value
+    , nearby : List NearbyPeer
     }
 
 
@@ -120,6 +122,7 @@ init defaultMode =
     , mode = defaultMode
     , address = ""
     , portNum = InputInt.init (Just 2581)
+    , nearby = []
     }
 
 
@@ -169,6 +172,7 @@ view model =
                             { onInput = PortChanged, value = model.portNum }
                         ]
                     ]
+                , viewNearby model.nearby
                 , Container.view [ Container.horizontal, Container.alignCenterY, Container.spaceBetween, Container.padTop_4 ]
                     [ statusString model
                     , Button.view [ Button.primary ] { label = [ text "Done" ], onClick = Close }
@@ -178,10 +182,31 @@ view model =
         }
 
 
+viewNearby : List NearbyPeer -> Html Msg
+viewNearby nearby =
+    if List.isEmpty nearby then
+        text ""
+
+    else
+        Container.view [ Container.vertical, Container.gap_1 ]
+            (Text.view [ Text.color Theme.baseForeground ] [ text "Nearby devices" ]
+                :: List.map
+                    (\peer ->
+                        Button.view [ Button.small, Button.outlined ]
+                            { label = [ text <| peer.name ++ " (" ++ peer.address ++ ")" ]
+                            , onClick = NearbyChosen peer
+                            }
+                    )
+                    nearby
+            )
+
+
 type Msg
     = ModeChanged Mode
     | AddressChanged String
     | PortChanged InputInt.Value
+    | PeerFound NearbyPeer
+    | NearbyChosen NearbyPeer
     | Open
     | Close
     | NoOp
@@ -199,6 +224,22 @@ update msg model =
         PortChanged portNum ->
             ( { model | portNum = portNum }, Cmd.none )
 
+        PeerFound peer ->
+            -- Peers are found again if they come back after going quiet
+            if List.member peer model.nearby then
+                ( model, Cmd.none )
+
+            else
+                ( { model | nearby = model.nearby ++ [ peer ] }, Cmd.none )
+
+        NearbyChosen peer ->
+            case splitAddress peer.address of
+                Just ( address, portNum ) ->
+                    ( { model | mode = Connect, address = address, portNum = InputInt.init (Just portNum) }, Cmd.none )
+
+                Nothing ->
+                    ( model, Cmd.none )
+
         Open ->
             ( { model | isOpen = True }, Browser.Dom.focus "peer-address-field" |> Task.attempt (\_ -> NoOp) )
 
@@ -209,6 +250,36 @@ update msg model =
             ( model, Cmd.none )
 
 
+{-| Start looking for nearby devices when the dialog opens, and stop when it closes. Found devices are sent back on
+`returnPath` as `PeerFound` responses.
+-}
+browseCmd : List String -> Msg -> Cmd msg
+browseCmd returnPath msg =
+    case msg of
+        Open ->
+            Messaging.callBackend { returnPath = returnPath, message = Messaging.BrowsePeers }
+
+        Close ->
+            Messaging.callBackend { returnPath = returnPath, message = Messaging.StopBrowsing }
+
+        _ ->
+            Cmd.none
+
+
+{-| Split an address like `192.168.1.2:2581` into its host and port.
+-}
+splitAddress : String -> Maybe ( String, Int )
+splitAddress address =
+    String.indexes ":" address
+        |> List.reverse
+        |> List.head
+        |> Maybe.andThen
+            (\index ->
+                String.toInt (String.dropLeft (index + 1) address)
+                    |> Maybe.map (Tuple.pair (String.left index address))
+            )
+
+
 errorText : String -> Html msg
 errorText t =
     Text.view [ Text.color Theme.dangerForeground ] [ text t ]
@@ -65,6 +65,8 @@ type alias Model =
     , outDir : String
     , isConnected : Bool
     , messages : List String
+    , progress : Maybe Messaging.TransferProgress
+    , listeningOn : List String
     }
 
 
@@ -79,6 +81,8 @@ init platform =
     , outDir = ""
     , isConnected = False
     , messages = []
+    , progress = Nothing
+    , listeningOn = []
     }
 
 
@@ -107,6 +111,7 @@ view convertMsg viewToolbar model =
                 ]
                 [ Button.view [] { label = [ text "Configure source..." ], onClick = SourceMsg Peer.Open }
                 , Peer.statusString model.source
+                , Components.viewListening model.listeningOn
                 , Html.map SourceMsg (Peer.view model.source)
                 ]
         , Html.map convertMsg <|
@@ -191,6 +196,7 @@ view convertMsg viewToolbar model =
                             [ InputText.view [] { onInput = OutDirChanged, value = model.outDir }
                             , Button.view [ Button.primary ] { label = [ text "Receive selected files" ], onClick = ReceiveFiles }
                             ]
+                        , Components.viewTransferProgress CancelTransfer model.progress
                         ]
         ]
 
@@ -205,6 +211,7 @@ type Msg
     | Connect
     | ReceiveText
     | ReceiveFiles
+    | CancelTransfer
     | ReceiveResponse (Messaging.Message Messaging.Response)
 
 
@@ -244,7 +251,9 @@ update msg model =
                 ( subModel, subCmd ) =
                     Peer.update subMsg model.source
             in
-            ( { model | source = subModel }, Cmd.map SourceMsg subCmd )
+            ( { model | source = subModel }
+            , Cmd.batch [ Cmd.map SourceMsg subCmd, Peer.browseCmd [ "Receive", "Peer" ] subMsg ]
+            )
 
         Connect ->
             ( { model | isConnected = False }
@@ -293,13 +302,24 @@ update msg model =
                 }
             )
 
+        CancelTransfer ->
+            ( { model | progress = Nothing }
+            , Messaging.callBackend
+                { returnPath = [ "Receive" ]
+                , message = Messaging.Cancel { role = "receiver" }
+                }
+            )
+
         ReceiveResponse { returnPath, message } ->
             case ( returnPath, message ) of
+                ( _, Messaging.Listening addresses ) ->
+                    ( { model | listeningOn = addresses }, Cmd.none )
+
                 ( [ "Text" ], Messaging.SetupComplete ) ->
-                    ( { model | isConnected = True }, Messaging.callBackend <| { returnPath = [ "Receive" ], message = Messaging.ReceiveText } )
+                    ( { model | isConnected = True, listeningOn = [] }, Messaging.callBackend <| { returnPath = [ "Receive" ], message = Messaging.ReceiveText } )
 
                 ( [ "Files" ], Messaging.SetupComplete ) ->
-                    ( { model | isConnected = True }
+                    ( { model | isConnected = True, listeningOn = [] }
                     , Messaging.callBackend
                         { returnPath = [ "Receive" ]
                         , message = Messaging.ReceiveFileList
@@ -319,8 +339,18 @@ update msg model =
                     , Cmd.none
                     )
 
+                ( [ "Peer" ], Messaging.PeerFound peer ) ->
+                    if peer.role == "sender" then
+                        update (SourceMsg (Peer.PeerFound peer)) model
+
+                    else
+                        ( model, Cmd.none )
+
+                ( _, Messaging.Progress current ) ->
+                    ( { model | progress = Just current }, Cmd.none )
+
                 ( _, Messaging.Error error ) ->
-                    ( { model | messages = model.messages ++ [ error ] }, Cmd.none )
+                    ( { model | messages = model.messages ++ [ error ], listeningOn = [] }, Cmd.none )
 
                 _ ->
                     ( model, Cmd.none )
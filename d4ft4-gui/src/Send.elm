@@ -56,6 +56,8 @@ type alias Model =
     , destination : Peer.Model
     , isSuccess : Bool
     , messages : List String
+    , progress : Maybe Messaging.TransferProgress
+    , listeningOn : List String
     }
 
 
@@ -68,6 +70,8 @@ init =
     , destination = Peer.init Peer.Connect
     , isSuccess = False
     , messages = []
+    , progress = Nothing
+    , listeningOn = []
     }
 
 
@@ -134,6 +138,7 @@ view convertMsg viewToolbar model =
                             [ Button.view [ Button.primary ] { label = [ text "Pick File" ], onClick = SelectFile }
                             , Button.view [ Button.danger ] { label = [ text "Delete" ], onClick = DeleteSelectedFiles }
                             ]
+                        , Components.viewTransferProgress CancelTransfer model.progress
                         ]
         , Html.map convertMsg <|
             Container.view
@@ -156,6 +161,7 @@ view convertMsg viewToolbar model =
                 ]
                 [ Button.view [] { label = [ text "Configure destination..." ], onClick = DestinationMsg Peer.Open }
                 , Peer.statusString model.destination
+                , Components.viewListening model.listeningOn
                 , if model.isSuccess then
                     Text.view [ Text.color Theme.primaryForeground ] [ text "Success!" ]
 
@@ -176,6 +182,7 @@ type Msg
     | DeleteSelectedFiles
     | DestinationMsg Peer.Msg
     | Send
+    | CancelTransfer
     | ReceiveResponse (Messaging.Message Messaging.Response)
     | SelectFile
     | PathAdded (Maybe String)
@@ -222,7 +229,9 @@ update msg model =
                 ( subModel, subCmd ) =
                     Peer.update subMsg model.destination
             in
-            ( { model | destination = subModel }, Cmd.map DestinationMsg subCmd )
+            ( { model | destination = subModel }
+            , Cmd.batch [ Cmd.map DestinationMsg subCmd, Peer.browseCmd [ "Send", "Peer" ] subMsg ]
+            )
 
         Send ->
             ( { model | isSuccess = False }
@@ -242,10 +251,21 @@ update msg model =
                     Cmd.none
             )
 
+        CancelTransfer ->
+            ( { model | progress = Nothing }
+            , Messaging.callBackend
+                { returnPath = [ "Send" ]
+                , message = Messaging.Cancel { role = "sender" }
+                }
+            )
+
         ReceiveResponse { returnPath, message } ->
             case ( returnPath, message ) of
+                ( _, Messaging.Listening addresses ) ->
+                    ( { model | listeningOn = addresses }, Cmd.none )
+
                 ( [ "Text" ], Messaging.SetupComplete ) ->
-                    ( model
+                    ( { model | listeningOn = [] }
                     , Messaging.callBackend
                         { returnPath = [ "Send" ]
                         , message = Messaging.SendText { text = model.text }
@@ -253,7 +273,7 @@ update msg model =
                     )
 
                 ( [ "Files" ], Messaging.SetupComplete ) ->
-                    ( model
+                    ( { model | listeningOn = [] }
                     , Messaging.callBackend
                         { returnPath = [ "Send" ]
                         , message = Messaging.SendFiles { names = model.files |> List.filter .selected |> List.map .name }
@@ -266,8 +286,18 @@ update msg model =
                 ( _, Messaging.FileSelected name ) ->
                     ( { model | files = model.files ++ [ initLoadedFile name ] }, Cmd.none )
 
+                ( [ "Peer" ], Messaging.PeerFound peer ) ->
+                    if peer.role == "receiver" then
+                        update (DestinationMsg (Peer.PeerFound peer)) model
+
+                    else
+                        ( model, Cmd.none )
+
+                ( _, Messaging.Progress current ) ->
+                    ( { model | progress = Just current }, Cmd.none )
+
                 ( _, Messaging.Error error ) ->
-                    ( { model | messages = model.messages ++ [ error ] }, Cmd.none )
+                    ( { model | messages = model.messages ++ [ error ], listeningOn = [] }, Cmd.none )
 
                 _ ->
                     ( model, Cmd.none )
@@ -0,0 +1,19 @@
+use tokio::net::TcpListener;
+
+/// The address the relay listens on if none is given.
+const DEFAULT_ADDRESS: &str = "0.0.0.0:2582";
+
+/// Pairs up d4ft4 clients by rendezvous ID and forwards their encrypted streams between them. Takes the address to
+/// listen on as its only argument.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+
+    let listener = TcpListener::bind(&address).await?;
+    eprintln!("relay listening on {}", listener.local_addr()?);
+
+    d4ft4::serve_relay(listener).await?;
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::{D4FTError, D4FTResult};
+
+/// Cancels whatever a connection is doing, from anywhere. Clones share the same state, so one can be handed to another
+/// task, such as a cancel button, while the connection is busy. Once cancelled, the operation in progress stops with
+/// [`D4FTError::Cancelled`], the other end is sent the reason, and the connection can't be used any more.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Set once cancelled. Only the first reason is kept.
+    reason: Mutex<Option<String>>,
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel, giving a reason to pass on to the other end. Cancelling again does nothing.
+    pub fn cancel(&self, reason: impl Into<String>) {
+        let mut current = self
+            .inner
+            .reason
+            .lock()
+            .expect("Cancel lock should not be poisoned");
+        if current.is_none() {
+            *current = Some(reason.into());
+            self.inner.notify.notify_waiters();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    /// The reason given when cancelling, or `None` if this hasn't been cancelled.
+    pub fn reason(&self) -> Option<String> {
+        self.inner
+            .reason
+            .lock()
+            .expect("Cancel lock should not be poisoned")
+            .clone()
+    }
+
+    /// Wait until this is cancelled, returning the reason.
+    pub async fn cancelled(&self) -> String {
+        loop {
+            // Registered before checking, so a cancel in between isn't missed
+            let notified = self.inner.notify.notified();
+            if let Some(reason) = self.reason() {
+                return reason;
+            }
+            notified.await;
+        }
+    }
+
+    /// Run `operation` until it finishes or this is cancelled, whichever comes first.
+    pub(crate) async fn run<T>(
+        &self,
+        operation: impl Future<Output = D4FTResult<T>>,
+    ) -> D4FTResult<T> {
+        tokio::select! {
+            biased;
+            reason = self.cancelled() => Err(D4FTError::Cancelled { reason }),
+            result = operation => result,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::Read;
+
+use crate::error::{D4FTError, D4FTResult};
+use crate::protocol::Compression;
+
+/// Codecs this end supports, in order of preference.
+pub(crate) const SUPPORTED: &[Compression] = &[Compression::Zstd, Compression::Lz4];
+
+const ZSTD_LEVEL: i32 = 3;
+
+// Flag byte at the start of each message, saying whether the rest of it is compressed
+const FLAG_RAW: u8 = 0;
+const FLAG_COMPRESSED: u8 = 1;
+
+/// Pick the first of the peer's codecs that we also support.
+pub(crate) fn negotiate(offered: &[Compression]) -> Option<Compression> {
+    offered
+        .iter()
+        .copied()
+        .find(|codec| SUPPORTED.contains(codec))
+}
+
+/// Compress a message and add the flag byte. Messages that don't get smaller are sent as-is, so data that is already
+/// compressed doesn't pay for it twice.
+pub(crate) fn compress(codec: Compression, data: &[u8]) -> Vec<u8> {
+    let compressed = match codec {
+        Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+        Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+        Compression::Unsupported => None,
+    };
+
+    let (flag, body) = match &compressed {
+        Some(compressed) if compressed.len() < data.len() => (FLAG_COMPRESSED, &compressed[..]),
+        _ => (FLAG_RAW, data),
+    };
+
+    let mut message = Vec::with_capacity(body.len() + 1);
+    message.push(flag);
+    message.extend_from_slice(body);
+    message
+}
+
+/// Remove the flag byte from a message, and decompress it if needed. Messages that would decompress to more than
+/// `max_size` bytes are rejected, so a small malicious message can't decompress into a huge one.
+pub(crate) fn decompress(
+    codec: Compression,
+    mut data: Vec<u8>,
+    max_size: u64,
+) -> D4FTResult<Vec<u8>> {
+    let Some(&flag) = data.first() else {
+        return Err(D4FTError::MalformedMessage {
+            msg: "missing compression flag".to_string(),
+        });
+    };
+
+    match flag {
+        FLAG_RAW => {
+            data.remove(0);
+            Ok(data)
+        }
+        FLAG_COMPRESSED => match codec {
+            Compression::Zstd => {
+                // Read one byte past the limit rather than trusting the frame's content size, so the output only
+                // grows as far as the data actually decompresses
+                let decoder = zstd::stream::read::Decoder::new(&data[1..]).map_err(|err| {
+                    D4FTError::DecompressionError {
+                        msg: err.to_string(),
+                    }
+                })?;
+                let mut decompressed = Vec::new();
+                decoder
+                    .take(max_size + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|err| D4FTError::DecompressionError {
+                        msg: err.to_string(),
+                    })?;
+                if decompressed.len() as u64 > max_size {
+                    return Err(D4FTError::MessageTooLarge {
+                        size: decompressed.len() as u64,
+                        limit: max_size,
+                    });
+                }
+                Ok(decompressed)
+            }
+            Compression::Lz4 => {
+                let (size, _) = lz4_flex::block::uncompressed_size(&data[1..]).map_err(|err| {
+                    D4FTError::DecompressionError {
+                        msg: err.to_string(),
+                    }
+                })?;
+                if size as u64 > max_size {
+                    return Err(D4FTError::MessageTooLarge {
+                        size: size as u64,
+                        limit: max_size,
+                    });
+                }
+                lz4_flex::decompress_size_prepended(&data[1..]).map_err(|err| {
+                    D4FTError::DecompressionError {
+                        msg: err.to_string(),
+                    }
+                })
+            }
+            Compression::Unsupported => Err(D4FTError::DecompressionError {
+                msg: "unsupported codec".to_string(),
+            }),
+        },
+        _ => Err(D4FTError::MalformedMessage {
+            msg: format!("unknown compression flag {flag}"),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"hello hello hello hello hello hello hello".repeat(100);
+        for &codec in SUPPORTED {
+            let message = compress(codec, &data);
+            assert_eq!(message[0], FLAG_COMPRESSED);
+            assert_eq!(decompress(codec, message, data.len() as u64).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_output() {
+        let data = vec![0u8; 1 << 20];
+        for &codec in SUPPORTED {
+            let message = compress(codec, &data);
+            assert!(matches!(
+                decompress(codec, message, 1024),
+                Err(D4FTError::MessageTooLarge { limit: 1024, .. })
+            ));
+        }
+    }
+}
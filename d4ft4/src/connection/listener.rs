@@ -0,0 +1,117 @@
+use crate::connection::{
+    handshake_listen, init_peer_stream, ConnectionOptions, Peer, Receiver, Sender,
+};
+use crate::{D4FTError, D4FTResult};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// A socket that is listening for the other end, but hasn't accepted it yet. Binding first means the address, and port
+/// if binding to port 0, can be shown to the user before waiting for the other end to connect.
+#[derive(Debug)]
+pub struct Listener {
+    listeners: Vec<TcpListener>,
+}
+
+impl Listener {
+    /// Start listening on every address that `address` resolves to, such as both `127.0.0.1` and `::1` for
+    /// `localhost`. Addresses that can't be bound are skipped, as long as at least one of them can be. With port 0, the
+    /// port picked for the first address is used for the rest too, so they can all be reached on one port.
+    pub async fn bind<A: ToSocketAddrs>(address: A) -> D4FTResult<Self> {
+        let mut listeners = Vec::new();
+        let mut bound_port = None;
+        let mut last_error = None;
+        for mut address in tokio::net::lookup_host(address)
+            .await
+            .map_err(|source| D4FTError::SocketError { source })?
+        {
+            if let (0, Some(port)) = (address.port(), bound_port) {
+                address.set_port(port);
+            }
+            match TcpListener::bind(address).await {
+                Ok(listener) => {
+                    if bound_port.is_none() {
+                        bound_port = listener.local_addr().ok().map(|address| address.port());
+                    }
+                    listeners.push(listener);
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        if listeners.is_empty() {
+            return Err(D4FTError::SocketError {
+                source: last_error.unwrap_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "could not resolve to any addresses",
+                    )
+                }),
+            });
+        }
+        Ok(Self { listeners })
+    }
+
+    /// The addresses being listened on, with the actual port when port 0 was asked for.
+    pub fn local_addrs(&self) -> D4FTResult<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .map(|listener| {
+                listener
+                    .local_addr()
+                    .map_err(|source| D4FTError::SocketError { source })
+            })
+            .collect()
+    }
+
+    /// Wait for the other end to connect, and set up a sender with it. The listener is closed once it has connected.
+    pub async fn accept_send(
+        self,
+        password: String,
+        options: ConnectionOptions,
+    ) -> D4FTResult<Sender> {
+        handshake_listen(self.accept().await?, password, options).await
+    }
+
+    /// Wait for the other end to connect, and set up a receiver with it. The listener is closed once it has connected.
+    pub async fn accept_receive(
+        self,
+        password: String,
+        options: ConnectionOptions,
+    ) -> D4FTResult<Receiver> {
+        handshake_listen(self.accept().await?, password, options).await
+    }
+
+    /// Wait for the other end to connect, and set up a duplex peer with it. The listener is closed once it has connected.
+    pub async fn accept_peer(
+        self,
+        password: String,
+        options: ConnectionOptions,
+    ) -> D4FTResult<Peer> {
+        init_peer_stream(self.accept().await?, true, password, options).await
+    }
+
+    /// Accept the first connection on any of the addresses, closing the listener.
+    pub(super) async fn accept(self) -> D4FTResult<TcpStream> {
+        self.accept_next()
+            .await
+            .map_err(|source| D4FTError::SocketError { source })
+    }
+
+    /// Accept the next connection on any of the addresses, and carry on listening.
+    pub(super) async fn accept_next(&self) -> std::io::Result<TcpStream> {
+        let accepts = self
+            .listeners
+            .iter()
+            .map(|listener| Box::pin(listener.accept()));
+        let (accepted, _, _) = futures::future::select_all(accepts).await;
+        accepted.map(|(stream, _)| stream)
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self {
+            listeners: vec![listener],
+        }
+    }
+}
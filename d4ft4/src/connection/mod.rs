@@ -1,31 +1,217 @@
-use crate::{encoding, protocol, D4FTError, D4FTResult};
-use tokio::net::{tcp, TcpListener, TcpStream, ToSocketAddrs};
+use crate::cancel::CancelToken;
+use crate::progress::ProgressTracker;
+use crate::protocol::{Capability, Kdf, Version};
+use crate::{compression, encoding, pake, protocol, D4FTError, D4FTResult};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
 
+mod listener;
+mod peer;
+#[cfg(feature = "quic")]
+mod quic;
 mod receive;
+mod relay;
 mod send;
+mod server;
+#[cfg(unix)]
+mod unix;
 
-pub use receive::Receiver;
+pub use listener::Listener;
+pub use peer::{init_peer, init_peer_stream, Peer};
+#[cfg(feature = "quic")]
+pub use quic::{init_receive_quic, init_send_quic, QuicReceiver, QuicSender};
+pub use receive::{Receiver, Transfer};
+pub use relay::{init_receive_relay, init_send_relay};
 pub use send::Sender;
+pub use server::{Incoming, Server};
+#[cfg(unix)]
+pub use unix::{init_receive_unix, init_send_unix};
 
-pub trait Connection {}
+/// Optional capabilities supported by this end.
+const CAPABILITIES: &[Capability] = &[
+    Capability::Compression,
+    Capability::Hashing,
+    Capability::Resume,
+    Capability::Folders,
+    Capability::Sessions,
+    Capability::Duplex,
+    Capability::Cancel,
+];
 
-trait InitConnection: Connection {
+pub trait Connection {
+    /// The protocol features agreed on with the other end during the handshake.
+    fn features(&self) -> &Features;
+}
+
+/// Protocol features agreed on by both ends of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Features {
+    pub peer_version: Version,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Features {
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Check for a capability, returning an error if it isn't supported.
+    pub(crate) fn require(&self, capability: Capability) -> D4FTResult<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(D4FTError::UnsupportedCapability {
+                capability,
+                peer_version: self.peer_version,
+            })
+        }
+    }
+}
+
+trait InitConnection<T>: Connection {
     const IS_SENDER: bool;
+    /// Whether this end can both send and receive, in which case the other end must too.
+    const IS_DUPLEX: bool = false;
     fn init(
-        encryptor: encoding::Encryptor<tcp::OwnedWriteHalf>,
-        decryptor: encoding::Decryptor<tcp::OwnedReadHalf>,
+        encryptor: encoding::Encryptor<WriteHalf<T>>,
+        decryptor: encoding::Decryptor<ReadHalf<T>>,
+        features: Features,
     ) -> Self;
 }
 
+/// The parts of an open connection, for handing it between a [`Sender`] and a [`Receiver`].
+struct Parts<T> {
+    encryptor: encoding::Encryptor<WriteHalf<T>>,
+    decryptor: encoding::Decryptor<ReadHalf<T>>,
+    features: Features,
+    progress: ProgressTracker,
+    cancel: CancelToken,
+}
+
+/// Finish an operation run with [`CancelToken::run`]. If it was cancelled from this end, the other end is sent the reason
+/// in an abort frame. If writing failed because the other end aborted and hung up, its reason is returned instead.
+async fn settle<T, W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    result: D4FTResult<T>,
+    cancel: &CancelToken,
+    features: &Features,
+    encryptor: &mut encoding::Encryptor<W>,
+    decryptor: &mut encoding::Decryptor<R>,
+) -> D4FTResult<T> {
+    match (result, cancel.reason()) {
+        (Err(D4FTError::Cancelled { .. }), Some(reason)) => {
+            // Without the capability the other end wouldn't understand the abort frame, so it only sees the session end
+            let abort = if features.supports(Capability::Cancel) {
+                encryptor.abort(reason.clone()).await
+            } else {
+                encryptor.shutdown().await
+            };
+            abort.and(Err(D4FTError::Cancelled { reason }))
+        }
+        (Err(err @ D4FTError::EncodeWriteError { .. }), _) => {
+            match decryptor.abort_reason().await {
+                Some(reason) => Err(D4FTError::Cancelled { reason }),
+                None => Err(err),
+            }
+        }
+        (result, _) => result,
+    }
+}
+
+/// Options for setting up a connection. The defaults suit most uses.
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    limits: encoding::Limits,
+    kdf: Kdf,
+    min_kdf: Kdf,
+    max_kdf: Kdf,
+    /// The capabilities offered to the other end. Only changed by tests, to stand in for peers from other versions.
+    capabilities: Vec<Capability>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            limits: encoding::Limits::default(),
+            kdf: Kdf::default(),
+            min_kdf: Kdf::STANDARD,
+            max_kdf: Kdf::ARGON2ID,
+            capabilities: CAPABILITIES.to_vec(),
+        }
+    }
+}
+
+impl ConnectionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the largest handshake or control message, such as a file list, that will be accepted from the other end.
+    pub fn max_control_size(mut self, bytes: u64) -> Self {
+        self.limits.control = bytes;
+        self
+    }
+
+    /// Set the largest chunk of file data that will be accepted from the other end.
+    pub fn max_chunk_size(mut self, bytes: u64) -> Self {
+        self.limits.chunk = bytes;
+        self
+    }
+
+    /// Set the KDF profile run on the password when connecting. The listening end picks the profile it is given.
+    pub fn kdf(mut self, kdf: Kdf) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    /// Set the cheapest KDF profile accepted when listening, so a connecting end can't downgrade it any further. The
+    /// default is [`Kdf::STANDARD`], lower it to [`Kdf::MOBILE`] to let phones connect.
+    pub fn min_kdf(mut self, kdf: Kdf) -> Self {
+        self.min_kdf = kdf;
+        self
+    }
+
+    /// Set the most expensive KDF profile accepted when listening. The connecting end picks the profile before it has
+    /// proved it knows the password, so this bounds the memory and time anyone who can connect can make us spend. The
+    /// default is [`Kdf::ARGON2ID`], which also allows [`Kdf::STANDARD`].
+    pub fn max_kdf(mut self, kdf: Kdf) -> Self {
+        self.max_kdf = kdf;
+        self
+    }
+}
+
 pub async fn init_send<A: ToSocketAddrs>(
     listen: bool,
     address: A,
     password: String,
 ) -> D4FTResult<Sender> {
+    init_send_with_options(listen, address, password, ConnectionOptions::default()).await
+}
+
+pub async fn init_send_with_options<A: ToSocketAddrs>(
+    listen: bool,
+    address: A,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Sender> {
+    if listen {
+        init_listen(address, password, options).await
+    } else {
+        init_connect(address, password, options).await
+    }
+}
+
+/// Set up a sender over a stream that is already open, such as a Unix socket, TLS stream or in-memory pipe. `listen`
+/// picks which side of the handshake this end takes, and the other end must take the other side.
+pub async fn init_send_stream<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: T,
+    listen: bool,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Sender<T>> {
     if listen {
-        init_listen(address, password).await
+        handshake_listen(stream, password, options).await
     } else {
-        init_connect(address, password).await
+        handshake_connect(stream, password, options).await
     }
 }
 
@@ -34,115 +220,511 @@ pub async fn init_receive<A: ToSocketAddrs>(
     address: A,
     password: String,
 ) -> D4FTResult<Receiver> {
+    init_receive_with_options(listen, address, password, ConnectionOptions::default()).await
+}
+
+pub async fn init_receive_with_options<A: ToSocketAddrs>(
+    listen: bool,
+    address: A,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Receiver> {
+    if listen {
+        init_listen(address, password, options).await
+    } else {
+        init_connect(address, password, options).await
+    }
+}
+
+/// Set up a receiver over a stream that is already open. See [`init_send_stream`].
+pub async fn init_receive_stream<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: T,
+    listen: bool,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Receiver<T>> {
     if listen {
-        init_listen(address, password).await
+        handshake_listen(stream, password, options).await
     } else {
-        init_connect(address, password).await
+        handshake_connect(stream, password, options).await
     }
 }
 
-async fn init_listen<A: ToSocketAddrs, Conn: InitConnection>(
+async fn init_listen<A: ToSocketAddrs, Conn: InitConnection<TcpStream>>(
     address: A,
     password: String,
+    options: ConnectionOptions,
 ) -> D4FTResult<Conn> {
-    let (mut socket, _) = TcpListener::bind(address)
-        .await
-        .map_err(|source| D4FTError::SocketError { source })?
-        .accept()
+    let socket = Listener::bind(address).await?.accept().await?;
+    handshake_listen(socket, password, options).await
+}
+
+/// Take the listening side of the handshake, which answers the connecting end's handshake.
+async fn handshake_listen<T: AsyncRead + AsyncWrite + Unpin, Conn: InitConnection<T>>(
+    socket: T,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Conn> {
+    handshake_listen_with_secret(socket, password, options)
         .await
-        .map_err(|source| D4FTError::SocketError { source })?;
+        .map(|(conn, _)| conn)
+}
 
-    let handshake = encoding::decode_plaintext::<protocol::Handshake, _>(&mut socket).await?;
+/// Like [`handshake_listen`], but also returns the secret for keying extra streams.
+async fn handshake_listen_with_secret<
+    T: AsyncRead + AsyncWrite + Unpin,
+    Conn: InitConnection<T>,
+>(
+    mut socket: T,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<(Conn, [u8; 32])> {
+    let (handshake, handshake_bytes) = encoding::decode_plaintext_with_bytes::<
+        protocol::Handshake,
+        _,
+    >(&mut socket, options.limits.control)
+    .await?;
 
-    let ivs = encoding::InitializationVectors::from_protocol(handshake.encryption)?;
-    let (rx_sock, tx_sock) = socket.into_split();
-    let (mut encryptor, decryptor) = tokio::join!(
-        encoding::Encryptor::new(
-            password.clone(),
-            ivs.server_client_salt,
-            &ivs.server_client_nonce,
-            tx_sock,
-        ),
-        encoding::Decryptor::new(
-            password,
-            ivs.client_server_salt,
-            &ivs.client_server_nonce,
-            rx_sock
-        ),
-    );
+    // Reject incompatible versions before the key exchange, since they might not do it the same way
+    if handshake.version.major != protocol::PROTOCOL_VERSION.major {
+        let reason = format!(
+            "incompatible version {}, expected {}.x",
+            handshake.version,
+            protocol::PROTOCOL_VERSION.major
+        );
+        encoding::encode_plaintext(
+            protocol::KeyExchange::Reject {
+                reason: reason.clone(),
+            },
+            &mut socket,
+        )
+        .await?;
+        return Err(D4FTError::RejectedHandshake { reason });
+    }
 
-    if handshake.version != "4" {
-        encryptor
-            .encode(&protocol::Response::Reject {
-                reason: "incompatible version".to_string(),
-            })
-            .await?;
-        return Err(D4FTError::RejectedHandshake {
-            reason: "incompatible version".to_string(),
+    let (Some(peer_share), Some(peer_dh_public)) = (&handshake.key_share, &handshake.dh_public)
+    else {
+        return Err(D4FTError::MalformedMessage {
+            msg: "handshake is missing key exchange values".to_string(),
         });
-    }
+    };
+    let peer_share = decode_key_share(peer_share)?;
+    let peer_dh_public = decode_key_share(peer_dh_public)?;
 
-    if handshake.is_sender == Conn::IS_SENDER {
+    let ivs = encoding::InitializationVectors::from_protocol(handshake.encryption)?;
+    if !ivs.kdf.is_valid()
+        || ivs.kdf.cost() < options.min_kdf.cost()
+        || !ivs.kdf.fits_within(&options.max_kdf)
+    {
         let reason = format!(
+            "KDF {:?} is not allowed, minimum is {:?} and maximum is {:?}",
+            ivs.kdf, options.min_kdf, options.max_kdf
+        );
+        encoding::encode_plaintext(
+            protocol::KeyExchange::Reject {
+                reason: reason.clone(),
+            },
+            &mut socket,
+        )
+        .await?;
+        return Err(D4FTError::RejectedHandshake { reason });
+    }
+    let session_id = ivs.session_id();
+    let password = encoding::harden_password(&password, &ivs).await?;
+    let pake = pake::Pake::start(&password, &session_id, false);
+    let dh = pake::EphemeralDh::generate();
+    let (share, dh_public) = (pake.share(), dh.public());
+
+    let secret = encoding::combine_secrets(
+        &pake.finish(&peer_share)?,
+        &dh.finish(peer_dh_public)?,
+        &session_id,
+    );
+    let transcript = [
+        &session_id[..],
+        &peer_share,
+        &peer_dh_public,
+        &share,
+        &dh_public,
+    ]
+    .concat();
+
+    encoding::encode_plaintext(
+        protocol::KeyExchange::Accept {
+            key_share: hex::encode_upper(share),
+            dh_public: hex::encode_upper(dh_public),
+            confirmation: hex::encode_upper(encoding::transcript_tag(
+                &secret,
+                encoding::CONFIRM_LISTENER,
+                &transcript,
+            )),
+            handshake_tag: hex::encode_upper(encoding::transcript_tag(
+                &secret,
+                encoding::HANDSHAKE_LISTENER,
+                &handshake_bytes,
+            )),
+        },
+        &mut socket,
+    )
+    .await?;
+
+    let peer_tags = encoding::decode_plaintext::<protocol::KeyConfirmation, _>(
+        &mut socket,
+        options.limits.control,
+    )
+    .await?;
+    verify_tags(
+        &secret,
+        encoding::CONFIRM_CONNECTOR,
+        &transcript,
+        &peer_tags.confirmation,
+        encoding::HANDSHAKE_CONNECTOR,
+        &handshake_bytes,
+        &peer_tags.handshake_tag,
+    )?;
+
+    let keys = encoding::SessionKeys::derive(&secret, &ivs);
+    let (rx_sock, tx_sock) = tokio::io::split(socket);
+    let mut encryptor =
+        encoding::Encryptor::new(&keys.server_client_key, &keys.server_client_nonce, tx_sock);
+    let mut decryptor = encoding::Decryptor::new(
+        &keys.client_server_key,
+        &keys.client_server_nonce,
+        rx_sock,
+        options.limits,
+    );
+
+    let role_mismatch = if handshake.duplex != Conn::IS_DUPLEX {
+        Some("only one end is a duplex peer".to_string())
+    } else if !Conn::IS_DUPLEX && handshake.is_sender == Conn::IS_SENDER {
+        Some(format!(
             "both ends are {}",
             if handshake.is_sender {
                 "sender"
             } else {
                 "receiver"
             }
-        );
+        ))
+    } else {
+        None
+    };
+    if let Some(reason) = role_mismatch {
         encryptor
-            .encode(&protocol::Response::Reject {
+            .encode(&protocol::HandshakeResponse::Reject {
                 reason: reason.clone(),
             })
             .await?;
         return Err(D4FTError::RejectedHandshake { reason });
     }
 
-    encryptor.encode(&protocol::Response::Accept).await?;
+    let features = Features {
+        peer_version: handshake.version,
+        capabilities: common_capabilities(&options.capabilities, &handshake.capabilities),
+    };
+    let compression = if features.supports(Capability::Compression) {
+        compression::negotiate(&handshake.compression)
+    } else {
+        None
+    };
+
+    encryptor
+        .encode(&protocol::HandshakeResponse::Accept {
+            version: Some(protocol::PROTOCOL_VERSION),
+            capabilities: features.capabilities.clone(),
+            compression,
+        })
+        .await?;
+
+    encryptor.set_compression(compression);
+    decryptor.set_compression(compression);
 
-    Ok(Conn::init(encryptor, decryptor))
+    Ok((
+        Conn::init(encryptor, decryptor, features),
+        keys.stream_secret,
+    ))
 }
 
-async fn init_connect<A: ToSocketAddrs, Conn: InitConnection>(
+async fn init_connect<A: ToSocketAddrs, Conn: InitConnection<TcpStream>>(
     address: A,
     password: String,
+    options: ConnectionOptions,
 ) -> D4FTResult<Conn> {
-    let mut socket = TcpStream::connect(address)
+    let socket = TcpStream::connect(address)
         .await
         .map_err(|source| D4FTError::SocketError { source })?;
 
-    let ivs = encoding::InitializationVectors::generate();
+    handshake_connect(socket, password, options).await
+}
 
-    encoding::encode_plaintext(
+/// Take the connecting side of the handshake, which picks the session parameters.
+async fn handshake_connect<T: AsyncRead + AsyncWrite + Unpin, Conn: InitConnection<T>>(
+    socket: T,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Conn> {
+    handshake_connect_with_secret(socket, password, options)
+        .await
+        .map(|(conn, _)| conn)
+}
+
+/// Like [`handshake_connect`], but also returns the secret for keying extra streams.
+async fn handshake_connect_with_secret<
+    T: AsyncRead + AsyncWrite + Unpin,
+    Conn: InitConnection<T>,
+>(
+    mut socket: T,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<(Conn, [u8; 32])> {
+    if !options.kdf.is_valid() {
+        return Err(D4FTError::InvalidKdf { kdf: options.kdf });
+    }
+    let ivs = encoding::InitializationVectors::generate(options.kdf);
+    let session_id = ivs.session_id();
+    let password = encoding::harden_password(&password, &ivs).await?;
+    let pake = pake::Pake::start(&password, &session_id, true);
+    let dh = pake::EphemeralDh::generate();
+    let (share, dh_public) = (pake.share(), dh.public());
+
+    let handshake_bytes = encoding::encode_plaintext(
         protocol::Handshake {
-            version: "4".to_string(),
+            version: protocol::PROTOCOL_VERSION,
             encryption: ivs.to_protocol(),
+            key_share: Some(hex::encode_upper(share)),
+            dh_public: Some(hex::encode_upper(dh_public)),
             is_sender: Conn::IS_SENDER,
+            duplex: Conn::IS_DUPLEX,
+            capabilities: options.capabilities.clone(),
+            compression: compression::SUPPORTED.to_vec(),
         },
         &mut socket,
     )
     .await?;
 
-    let (tx_sock, rx_sock) = socket.into_split();
-    let (mut decryptor, encryptor) = tokio::join!(
-        encoding::Decryptor::new(
-            password.clone(),
-            ivs.server_client_salt,
-            &ivs.server_client_nonce,
-            tx_sock,
-        ),
-        encoding::Encryptor::new(
-            password,
-            ivs.client_server_salt,
-            &ivs.client_server_nonce,
-            rx_sock
-        ),
+    let (peer_share, peer_dh_public, peer_confirmation, peer_handshake_tag) =
+        match encoding::decode_plaintext::<protocol::KeyExchange, _>(
+            &mut socket,
+            options.limits.control,
+        )
+        .await?
+        {
+            protocol::KeyExchange::Accept {
+                key_share,
+                dh_public,
+                confirmation,
+                handshake_tag,
+            } => (
+                decode_key_share(&key_share)?,
+                decode_key_share(&dh_public)?,
+                confirmation,
+                handshake_tag,
+            ),
+            protocol::KeyExchange::Reject { reason } => {
+                return Err(D4FTError::RejectedHandshake { reason })
+            }
+        };
+
+    let secret = encoding::combine_secrets(
+        &pake.finish(&peer_share)?,
+        &dh.finish(peer_dh_public)?,
+        &session_id,
     );
+    let transcript = [
+        &session_id[..],
+        &share,
+        &dh_public,
+        &peer_share,
+        &peer_dh_public,
+    ]
+    .concat();
 
-    if let protocol::Response::Reject { reason } = decryptor.decode::<protocol::Response>().await? {
-        return Err(D4FTError::RejectedHandshake { reason });
+    // Send our tags even if the listener's were wrong, so that it can tell what went wrong too
+    encoding::encode_plaintext(
+        protocol::KeyConfirmation {
+            confirmation: hex::encode_upper(encoding::transcript_tag(
+                &secret,
+                encoding::CONFIRM_CONNECTOR,
+                &transcript,
+            )),
+            handshake_tag: hex::encode_upper(encoding::transcript_tag(
+                &secret,
+                encoding::HANDSHAKE_CONNECTOR,
+                &handshake_bytes,
+            )),
+        },
+        &mut socket,
+    )
+    .await?;
+    verify_tags(
+        &secret,
+        encoding::CONFIRM_LISTENER,
+        &transcript,
+        &peer_confirmation,
+        encoding::HANDSHAKE_LISTENER,
+        &handshake_bytes,
+        &peer_handshake_tag,
+    )?;
+
+    let keys = encoding::SessionKeys::derive(&secret, &ivs);
+    let (tx_sock, rx_sock) = tokio::io::split(socket);
+    let mut decryptor = encoding::Decryptor::new(
+        &keys.server_client_key,
+        &keys.server_client_nonce,
+        tx_sock,
+        options.limits,
+    );
+    let mut encryptor =
+        encoding::Encryptor::new(&keys.client_server_key, &keys.client_server_nonce, rx_sock);
+
+    let (features, compression) = match decryptor.decode::<protocol::HandshakeResponse>().await? {
+        protocol::HandshakeResponse::Accept {
+            version,
+            capabilities,
+            compression,
+        } => (
+            Features {
+                peer_version: version.unwrap_or(protocol::PROTOCOL_VERSION),
+                capabilities: common_capabilities(&options.capabilities, &capabilities),
+            },
+            compression,
+        ),
+        protocol::HandshakeResponse::Reject { reason } => {
+            return Err(D4FTError::RejectedHandshake { reason })
+        }
+    };
+
+    encryptor.set_compression(compression);
+    decryptor.set_compression(compression);
+
+    Ok((
+        Conn::init(encryptor, decryptor, features),
+        keys.stream_secret,
+    ))
+}
+
+/// Check the other end's key confirmation tag, then its tag over the plaintext handshake. A bad confirmation tag means
+/// the passwords didn't match, while a bad handshake tag with a good confirmation tag means the handshake was modified.
+fn verify_tags(
+    secret: &[u8; 32],
+    confirm_label: &[u8],
+    transcript: &[u8],
+    confirmation: &str,
+    handshake_label: &[u8],
+    handshake_bytes: &[u8],
+    handshake_tag: &str,
+) -> D4FTResult<()> {
+    let confirmation =
+        hex::decode(confirmation).map_err(|source| D4FTError::HexDecodeError { source })?;
+    if !encoding::verify_transcript_tag(secret, confirm_label, transcript, &confirmation) {
+        return Err(D4FTError::WrongPassword);
+    }
+
+    let handshake_tag =
+        hex::decode(handshake_tag).map_err(|source| D4FTError::HexDecodeError { source })?;
+    if !encoding::verify_transcript_tag(secret, handshake_label, handshake_bytes, &handshake_tag) {
+        return Err(D4FTError::TamperedHandshake);
     }
 
-    Ok(Conn::init(encryptor, decryptor))
+    Ok(())
+}
+
+/// Decode a hex encoded PAKE share or public key from the other end.
+fn decode_key_share<const N: usize>(key_share: &str) -> D4FTResult<[u8; N]> {
+    let mut share = [0u8; N];
+    hex::decode_to_slice(key_share, &mut share)
+        .map_err(|source| D4FTError::HexDecodeError { source })?;
+    Ok(share)
+}
+
+/// Get the capabilities from the other end that this end also supports.
+fn common_capabilities(ours: &[Capability], offered: &[Capability]) -> Vec<Capability> {
+    ours.iter()
+        .copied()
+        .filter(|capability| offered.contains(capability))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn negotiates_shared_capabilities() {
+        let (a, b) = duplex(64 * 1024);
+        // Stands in for a peer from another version, which knows one capability we don't and lacks most of ours
+        let mut options = ConnectionOptions::new();
+        options.capabilities = vec![
+            Capability::Hashing,
+            Capability::Unsupported,
+            Capability::Sessions,
+        ];
+
+        let (receiver, sender) = tokio::join!(
+            init_receive_stream(a, true, "pw".into(), ConnectionOptions::new()),
+            init_send_stream(b, false, "pw".into(), options),
+        );
+        let (mut receiver, mut sender) = (receiver.unwrap(), sender.unwrap());
+
+        let shared = [Capability::Hashing, Capability::Sessions];
+        assert_eq!(receiver.features().capabilities, shared);
+        assert_eq!(sender.features().capabilities, shared);
+        assert!(matches!(
+            sender.send_tree(&["."]).await,
+            Err(D4FTError::UnsupportedCapability {
+                capability: Capability::Folders,
+                ..
+            })
+        ));
+
+        let (sent, received) = tokio::join!(
+            async {
+                sender.send_text("still works".into()).await?;
+                sender.close().await
+            },
+            async {
+                let text = receiver.receive_text().await?;
+                receiver.close().await?;
+                Ok::<_, D4FTError>(text)
+            },
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), "still works");
+    }
+
+    #[tokio::test]
+    async fn refuses_second_transfer_without_sessions() {
+        let (a, b) = duplex(64 * 1024);
+        // Stands in for a peer from before sessions, which only takes one transfer per connection
+        let mut options = ConnectionOptions::new();
+        options.capabilities = vec![Capability::Hashing];
+
+        let (receiver, sender) = tokio::join!(
+            init_receive_stream(a, true, "pw".into(), options),
+            init_send_stream(b, false, "pw".into(), ConnectionOptions::new()),
+        );
+        let (mut receiver, mut sender) = (receiver.unwrap(), sender.unwrap());
+
+        let (sent, received) = tokio::join!(
+            async {
+                sender.send_text("only".into()).await?;
+                let again = sender.send_text("again".into()).await;
+                sender.close().await?;
+                Ok::<_, D4FTError>(again)
+            },
+            async {
+                let text = receiver.receive_text().await?;
+                receiver.close().await?;
+                Ok::<_, D4FTError>(text)
+            },
+        );
+        assert!(matches!(
+            sent.unwrap(),
+            Err(D4FTError::UnsupportedCapability {
+                capability: Capability::Sessions,
+                ..
+            })
+        ));
+        assert_eq!(received.unwrap(), "only");
+    }
 }
@@ -0,0 +1,380 @@
+use crate::cancel::CancelToken;
+use crate::connection::{
+    handshake_connect, handshake_listen, Connection, ConnectionOptions, Features, InitConnection,
+    Listener, Parts, Receiver, Sender, Transfer,
+};
+use crate::encoding::{Decryptor, Encryptor};
+use crate::progress::{ProgressSink, ProgressTracker};
+use crate::{protocol, Capability, D4FTError, D4FTResult};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// A connection where either end can send, with the other end choosing whether to accept. Each transfer is started by
+/// one end and offered to the other, which picks it up with [`Peer::next_transfer`]. Only one transfer happens at a
+/// time: if both ends start one at once, the listening end's goes first, and the connecting end gets
+/// [`D4FTError::TransferPending`] until it has received it.
+pub struct Peer<T = TcpStream> {
+    /// The connection, set up for whichever direction the last transfer went in. Only empty while switching.
+    state: Option<State<T>>,
+    features: Features,
+    listen: bool,
+    /// The number of transfers this end has started.
+    transfers: u64,
+    /// A transfer the other end has begun, that it is waiting for this end to receive.
+    pending: Option<u64>,
+    /// Whether the other end has said it has nothing more to send.
+    goodbye: bool,
+    /// Shared with the sender and receiver underneath.
+    cancel: CancelToken,
+}
+
+enum State<T> {
+    Sending(Sender<T>),
+    Receiving(Receiver<T>),
+}
+
+impl<T> Connection for Peer<T> {
+    fn features(&self) -> &Features {
+        &self.features
+    }
+}
+
+impl<T> InitConnection<T> for Peer<T> {
+    const IS_SENDER: bool = false;
+    const IS_DUPLEX: bool = true;
+    fn init(
+        encryptor: Encryptor<WriteHalf<T>>,
+        decryptor: Decryptor<ReadHalf<T>>,
+        features: Features,
+    ) -> Self {
+        // Peers frame their own transfers, so the sender and receiver underneath mustn't add session framing too
+        let inner_features = Features {
+            peer_version: features.peer_version,
+            capabilities: features
+                .capabilities
+                .iter()
+                .copied()
+                .filter(|capability| *capability != Capability::Sessions)
+                .collect(),
+        };
+
+        let cancel = CancelToken::default();
+        Self {
+            state: Some(State::Receiving(Receiver::from_parts(Parts {
+                encryptor,
+                decryptor,
+                features: inner_features,
+                progress: ProgressTracker::default(),
+                cancel: cancel.clone(),
+            }))),
+            features,
+            listen: false,
+            transfers: 0,
+            pending: None,
+            goodbye: false,
+            cancel,
+        }
+    }
+}
+
+/// Set up a duplex peer. One end listens and the other connects, and both must be peers.
+pub async fn init_peer<A: ToSocketAddrs>(
+    listen: bool,
+    address: A,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Peer> {
+    if listen {
+        Listener::bind(address)
+            .await?
+            .accept_peer(password, options)
+            .await
+    } else {
+        let socket = TcpStream::connect(address)
+            .await
+            .map_err(|source| D4FTError::SocketError { source })?;
+        init_peer_stream(socket, false, password, options).await
+    }
+}
+
+/// Set up a duplex peer over a stream that is already open. See [`init_send_stream`](crate::init_send_stream).
+pub async fn init_peer_stream<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: T,
+    listen: bool,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Peer<T>> {
+    let mut peer: Peer<T> = if listen {
+        handshake_listen(stream, password, options).await?
+    } else {
+        handshake_connect(stream, password, options).await?
+    };
+
+    // A listener from before duplex peers would take this end for a receiver
+    peer.features.require(Capability::Duplex)?;
+    peer.listen = listen;
+    Ok(peer)
+}
+
+impl<T: AsyncRead + AsyncWrite> Peer<T> {
+    /// Set a function to be called with the progress of file transfers in either direction, or remove it with `None`.
+    pub fn set_progress_sink(&mut self, sink: Option<ProgressSink>) {
+        match self.state_mut() {
+            State::Sending(sender) => sender.set_progress_sink(sink),
+            State::Receiving(receiver) => receiver.set_progress_sink(sink),
+        }
+    }
+
+    /// Get a token that cancels whatever this peer is doing when it is cancelled, even while it is busy.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Send a piece of text, once the other end is ready to receive it.
+    pub async fn send_text(&mut self, text: String) -> D4FTResult<()> {
+        self.begin_send().await?.send_text(text).await
+    }
+
+    /// Send files without any directory structure. See [`Sender::send_flat_files`].
+    pub async fn send_flat_files(&mut self, files: Vec<(PathBuf, &mut File)>) -> D4FTResult<()> {
+        self.begin_send().await?.send_flat_files(files).await
+    }
+
+    /// Send files and directories, preserving the directory structure. See [`Sender::send_tree`].
+    pub async fn send_tree<P: AsRef<Path>>(&mut self, paths: &[P]) -> D4FTResult<()> {
+        self.features.require(Capability::Folders)?;
+        self.begin_send().await?.send_tree(paths).await
+    }
+
+    /// Wait for the other end to start a transfer, returning `None` once it has closed. Text is accepted straight away.
+    /// For files, the file list is returned, and [`Peer::receive_flat_files_fs`] or [`Peer::receive_tree_fs`] should be
+    /// called next to choose which of them to accept; a file list that is left unanswered is rejected when this end
+    /// next starts or waits for a transfer.
+    pub async fn next_transfer(&mut self) -> D4FTResult<Option<Transfer>> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                if self.goodbye {
+                    return Ok(None);
+                }
+
+                let pending = self.pending.take();
+                let receiver = self.receiver().await?;
+                let transfer = match pending {
+                    Some(transfer) => transfer,
+                    None => match receiver
+                        .decryptor
+                        .decode::<protocol::SessionMessage>()
+                        .await?
+                    {
+                        protocol::SessionMessage::Begin { transfer } => transfer,
+                        protocol::SessionMessage::Goodbye => {
+                            self.goodbye = true;
+                            return Ok(None);
+                        }
+                        message => {
+                            return Err(D4FTError::MalformedMessage {
+                                msg: format!("expected a transfer to begin, got {message:?}"),
+                            })
+                        }
+                    },
+                };
+
+                receiver
+                    .encryptor
+                    .encode(&protocol::SessionMessage::Ready { transfer })
+                    .await?;
+                receiver.receive_transfer().await.map(Some)
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    /// Accept files from the file list returned by [`Peer::next_transfer`]. See [`Receiver::receive_flat_files_fs`].
+    pub async fn receive_flat_files_fs(
+        &mut self,
+        allowlist: Vec<PathBuf>,
+        out_dir: Option<&Path>,
+    ) -> D4FTResult<()> {
+        self.offered_files()?
+            .receive_flat_files_fs(allowlist, out_dir)
+            .await
+    }
+
+    /// Accept files and directories from the file list returned by [`Peer::next_transfer`], preserving the directory
+    /// structure. See [`Receiver::receive_tree_fs`].
+    pub async fn receive_tree_fs(
+        &mut self,
+        allowlist: Vec<PathBuf>,
+        out_dir: Option<&Path>,
+    ) -> D4FTResult<()> {
+        self.offered_files()?
+            .receive_tree_fs(allowlist, out_dir)
+            .await
+    }
+
+    /// Say goodbye and close the connection, once the other end has finished what it was sending and closed too.
+    pub async fn close(mut self) -> D4FTResult<()> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                self.reject_offered_files().await?;
+                let mut goodbye = self.goodbye;
+                let (encryptor, decryptor) = self.halves();
+
+                encryptor.encode(&protocol::SessionMessage::Goodbye).await?;
+                // Anything the other end begins now is dropped, since it will see the goodbye too
+                while !goodbye {
+                    goodbye = matches!(
+                        decryptor.decode::<protocol::SessionMessage>().await?,
+                        protocol::SessionMessage::Goodbye
+                    );
+                }
+
+                encryptor.close().await?;
+                decryptor.expect_close().await
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    /// Begin a transfer from this end, and wait for the other end to be ready for it. Cancelling stops the wait, and once
+    /// the transfer has begun the sender returned handles cancelling itself.
+    async fn begin_send(&mut self) -> D4FTResult<&mut Sender<T>> {
+        let cancel = self.cancel.clone();
+        let result = cancel.run(self.wait_until_ready()).await;
+        self.settle(result).await?;
+        self.sender().await
+    }
+
+    /// Tell the other end about a new transfer from this end, and wait for it to say it is ready.
+    async fn wait_until_ready(&mut self) -> D4FTResult<()> {
+        if self.goodbye {
+            return Err(D4FTError::ConnectionClosed);
+        }
+        if self.pending.is_some() {
+            return Err(D4FTError::TransferPending);
+        }
+
+        self.transfers += 1;
+        let (transfer, listen) = (self.transfers, self.listen);
+        let sender = self.sender().await?;
+        sender
+            .encryptor
+            .encode(&protocol::SessionMessage::Begin { transfer })
+            .await?;
+
+        let interruption = loop {
+            match sender
+                .decryptor
+                .decode::<protocol::SessionMessage>()
+                .await?
+            {
+                protocol::SessionMessage::Ready { transfer: ready } if ready == transfer => {
+                    break None
+                }
+                // Both ends began at once, and the listening end goes first. The connecting end drops its own
+                // transfer, so the listening end skips over its begin and carries on waiting.
+                protocol::SessionMessage::Begin { .. } if listen => {}
+                protocol::SessionMessage::Begin { transfer: theirs } => break Some(Some(theirs)),
+                protocol::SessionMessage::Goodbye => break Some(None),
+                message => {
+                    return Err(D4FTError::MalformedMessage {
+                        msg: format!("expected the other end to be ready, got {message:?}"),
+                    })
+                }
+            }
+        };
+
+        match interruption {
+            None => Ok(()),
+            Some(Some(theirs)) => {
+                self.pending = Some(theirs);
+                Err(D4FTError::TransferPending)
+            }
+            Some(None) => {
+                self.goodbye = true;
+                Err(D4FTError::ConnectionClosed)
+            }
+        }
+    }
+
+    /// The connection set up for sending, switching it over if the last transfer was received.
+    async fn sender(&mut self) -> D4FTResult<&mut Sender<T>> {
+        self.reject_offered_files().await?;
+        if let Some(State::Receiving(_)) = self.state {
+            let Some(State::Receiving(receiver)) = self.state.take() else {
+                unreachable!()
+            };
+            self.state = Some(State::Sending(Sender::from_parts(receiver.into_parts())));
+        }
+
+        match self.state_mut() {
+            State::Sending(sender) => Ok(sender),
+            State::Receiving(_) => unreachable!("Peer should have switched to sending"),
+        }
+    }
+
+    /// The connection set up for receiving, switching it over if the last transfer was sent.
+    async fn receiver(&mut self) -> D4FTResult<&mut Receiver<T>> {
+        self.reject_offered_files().await?;
+        if let Some(State::Sending(_)) = self.state {
+            let Some(State::Sending(sender)) = self.state.take() else {
+                unreachable!()
+            };
+            self.state = Some(State::Receiving(Receiver::from_parts(sender.into_parts())));
+        }
+
+        match self.state_mut() {
+            State::Receiving(receiver) => Ok(receiver),
+            State::Sending(_) => unreachable!("Peer should have switched to receiving"),
+        }
+    }
+
+    /// The receiver holding a file list from the other end that hasn't been answered yet.
+    fn offered_files(&mut self) -> D4FTResult<&mut Receiver<T>> {
+        match self.state_mut() {
+            State::Receiving(receiver) if receiver.file_list.is_some() => Ok(receiver),
+            _ => Err(D4FTError::NoFileTransferPrepared),
+        }
+    }
+
+    /// Reject a file list from the other end that was never answered, since it is still waiting for an answer.
+    async fn reject_offered_files(&mut self) -> D4FTResult<()> {
+        if let State::Receiving(receiver) = self.state_mut() {
+            if receiver.file_list.take().is_some() {
+                receiver
+                    .encryptor
+                    .encode(&protocol::FileListResponse::Reject {
+                        reason: "receiver skipped the transfer".to_string(),
+                    })
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish an operation run with the cancel token, aborting the connection if it was cancelled.
+    async fn settle<R>(&mut self, result: D4FTResult<R>) -> D4FTResult<R> {
+        match self.state_mut() {
+            State::Sending(sender) => sender.settle(result).await,
+            State::Receiving(receiver) => receiver.settle(result).await,
+        }
+    }
+
+    /// The two directions of the connection, whichever way it is set up.
+    fn halves(&mut self) -> (&mut Encryptor<WriteHalf<T>>, &mut Decryptor<ReadHalf<T>>) {
+        match self.state_mut() {
+            State::Sending(sender) => (&mut sender.encryptor, &mut sender.decryptor),
+            State::Receiving(receiver) => (&mut receiver.encryptor, &mut receiver.decryptor),
+        }
+    }
+
+    fn state_mut(&mut self) -> &mut State<T> {
+        self.state
+            .as_mut()
+            .expect("Peer state is only empty while switching")
+    }
+}
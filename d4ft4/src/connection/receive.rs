@@ -1,110 +1,715 @@
-use crate::connection::{Connection, InitConnection};
-use crate::encoding::{Decryptor, Encryptor};
-use crate::{protocol, D4FTError, D4FTResult, FileListItem};
-use std::path::{Path, PathBuf};
-use tokio::fs::File;
-use tokio::net::tcp;
-
-pub struct Receiver {
-    encryptor: Encryptor<tcp::OwnedWriteHalf>,
-    decryptor: Decryptor<tcp::OwnedReadHalf>,
+use crate::cancel::CancelToken;
+use crate::connection::{settle, Connection, Features, InitConnection, Parts};
+use crate::encoding::{self, Decryptor, Encryptor};
+use crate::progress::{ProgressSink, ProgressTracker};
+use crate::{protocol, Capability, D4FTError, D4FTResult, FileListItem};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+
+const PARTIAL_EXTENSION: &str = ".d4ft4-partial";
+
+pub struct Receiver<T = TcpStream> {
+    pub(super) encryptor: Encryptor<WriteHalf<T>>,
+    pub(super) decryptor: Decryptor<ReadHalf<T>>,
+    pub(super) features: Features,
+    pub(super) file_list: Option<Vec<FileListItem>>,
+    progress: ProgressTracker,
+    cancel: CancelToken,
+    /// The transfer the sender has begun but not yet ended, with sessions.
+    open_transfer: Option<u64>,
+    /// Whether the sender has said it has nothing more to send.
+    goodbye: bool,
+}
+
+/// A transfer started by the other end, returned by [`Receiver::next_transfer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transfer {
+    /// Text, which has already been accepted.
+    Text(String),
+    /// The list of files on offer, which are accepted by passing an allowlist to one of the `receive_*_fs` functions.
+    Files(Vec<FileListItem>),
 }
 
-impl Connection for Receiver {}
+impl<T> Connection for Receiver<T> {
+    fn features(&self) -> &Features {
+        &self.features
+    }
+}
 
-impl InitConnection for Receiver {
+impl<T> InitConnection<T> for Receiver<T> {
     const IS_SENDER: bool = false;
     fn init(
-        encryptor: Encryptor<tcp::OwnedWriteHalf>,
-        decryptor: Decryptor<tcp::OwnedReadHalf>,
+        encryptor: Encryptor<WriteHalf<T>>,
+        decryptor: Decryptor<ReadHalf<T>>,
+        features: Features,
     ) -> Self {
-        Self {
+        Self::from_parts(Parts {
             encryptor,
             decryptor,
+            features,
+            progress: ProgressTracker::default(),
+            cancel: CancelToken::default(),
+        })
+    }
+}
+
+impl<T> Receiver<T> {
+    pub(super) fn from_parts(parts: Parts<T>) -> Self {
+        Self {
+            encryptor: parts.encryptor,
+            decryptor: parts.decryptor,
+            features: parts.features,
+            file_list: None,
+            progress: parts.progress,
+            cancel: parts.cancel,
+            open_transfer: None,
+            goodbye: false,
+        }
+    }
+
+    pub(super) fn into_parts(self) -> Parts<T> {
+        Parts {
+            encryptor: self.encryptor,
+            decryptor: self.decryptor,
+            features: self.features,
+            progress: self.progress,
+            cancel: self.cancel,
         }
     }
 }
 
-impl Receiver {
-    pub async fn receive_text(&mut self) -> D4FTResult<String> {
-        let transfer = self.decryptor.decode::<protocol::InitTransfer>().await?;
+impl<T: AsyncRead + AsyncWrite> Receiver<T> {
+    /// Set a function to be called with the progress of file transfers after every chunk received, or remove it with
+    /// `None`.
+    pub fn set_progress_sink(&mut self, sink: Option<ProgressSink>) {
+        self.progress.set_sink(sink);
+    }
 
-        match transfer {
+    /// Get a token that cancels whatever this connection is doing when it is cancelled, even while it is busy.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Close the connection, and wait for the other end to close it too. If the other end goes away without closing,
+    /// this returns [`D4FTError::Truncated`].
+    pub async fn close(mut self) -> D4FTResult<()> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                // With sessions, let the sender finish its last transfer and say goodbye first
+                if self.begin_transfer().await? && self.features.supports(Capability::Sessions) {
+                    return Err(D4FTError::MalformedMessage {
+                        msg: "expected goodbye, got another transfer".to_string(),
+                    });
+                }
+
+                self.encryptor.close().await?;
+                self.decryptor.expect_close().await
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    /// Wait for the other end to start its next transfer, returning `None` once it has nothing more to send. Text is
+    /// accepted straight away. For files, the file list is returned, and one of the `receive_*_fs` functions should be
+    /// called next to choose which of them to accept; a file list that is left unanswered is rejected when this is
+    /// called again. Both ends must support [`Capability::Sessions`].
+    pub async fn next_transfer(&mut self) -> D4FTResult<Option<Transfer>> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                self.features.require(Capability::Sessions)?;
+                if !self.begin_transfer().await? {
+                    return Ok(None);
+                }
+
+                self.receive_transfer().await.map(Some)
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    /// Read the start of a transfer, accepting it if it is text.
+    pub(super) async fn receive_transfer(&mut self) -> D4FTResult<Transfer> {
+        match self.decryptor.decode::<protocol::InitTransfer>().await? {
             protocol::InitTransfer::Text { text } => {
                 self.encryptor.encode(&protocol::Response::Accept).await?;
-                Ok(text)
+                Ok(Transfer::Text(text))
             }
-            protocol::InitTransfer::Files { .. } => {
-                let reason = "got files, wanted text".to_string();
-                self.encryptor
-                    .encode(&protocol::Response::Reject {
-                        reason: reason.clone(),
-                    })
-                    .await?;
-                Err(D4FTError::RejectedTransfer { reason })
+            protocol::InitTransfer::Files { files } => {
+                self.file_list = Some(files.clone());
+                Ok(Transfer::Files(files))
             }
         }
     }
 
+    pub async fn receive_text(&mut self) -> D4FTResult<String> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                if !self.begin_transfer().await? {
+                    return Err(D4FTError::ConnectionClosed);
+                }
+
+                let transfer = self.decryptor.decode::<protocol::InitTransfer>().await?;
+
+                match transfer {
+                    protocol::InitTransfer::Text { text } => {
+                        self.encryptor.encode(&protocol::Response::Accept).await?;
+                        Ok(text)
+                    }
+                    protocol::InitTransfer::Files { .. } => {
+                        let reason = "got files, wanted text".to_string();
+                        self.encryptor
+                            .encode(&protocol::Response::Reject {
+                                reason: reason.clone(),
+                            })
+                            .await?;
+                        Err(D4FTError::RejectedTransfer { reason })
+                    }
+                }
+            })
+            .await;
+        self.settle(result).await
+    }
+
     pub async fn receive_file_list(&mut self) -> D4FTResult<Vec<FileListItem>> {
-        let transfer = self.decryptor.decode::<protocol::InitTransfer>().await?;
-
-        match transfer {
-            protocol::InitTransfer::Text { .. } => {
-                let reason = "got text, wanted files".to_string();
-                self.encryptor
-                    .encode(&protocol::Response::Reject {
-                        reason: reason.clone(),
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                if !self.begin_transfer().await? {
+                    return Err(D4FTError::ConnectionClosed);
+                }
+
+                let transfer = self.decryptor.decode::<protocol::InitTransfer>().await?;
+
+                match transfer {
+                    protocol::InitTransfer::Text { .. } => {
+                        let reason = "got text, wanted files".to_string();
+                        self.encryptor
+                            .encode(&protocol::Response::Reject {
+                                reason: reason.clone(),
+                            })
+                            .await?;
+                        Err(D4FTError::RejectedTransfer { reason })
+                    }
+                    protocol::InitTransfer::Files { files } => {
+                        self.file_list = Some(files.clone());
+                        Ok(files)
+                    }
+                }
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    pub async fn receive_flat_files_fs(
+        &mut self,
+        allowlist: Vec<PathBuf>,
+        out_dir: Option<&Path>,
+    ) -> D4FTResult<()> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                let allowed = allowlist
+                    .iter()
+                    .map(PathBuf::as_path)
+                    .collect::<HashSet<_>>();
+                let total_size = self
+                    .file_list
+                    .take()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter(|item| allowed.contains(item.path()))
+                    .filter_map(FileListItem::size)
+                    .sum();
+
+                let out_dir = out_dir.unwrap_or(".".as_ref());
+
+                let files = allowlist
+                    .iter()
+                    .map(|path| {
+                        path.file_name()
+                            .map(|name| (path.clone(), out_dir.join(name)))
+                            .ok_or_else(|| D4FTError::CannotReadPath { path: path.clone() })
                     })
-                    .await?;
-                Err(D4FTError::RejectedTransfer { reason })
+                    .collect::<D4FTResult<_>>()?;
+
+                self.receive_files(allowlist, files, total_size).await
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    /// Receive files and directories sent with [`Sender::send_tree`](crate::Sender::send_tree), recreating their
+    /// directory structure inside `out_dir`. Only allowlisted items are created, along with the parent directories of
+    /// any allowlisted files. [`Receiver::receive_file_list`] must be called first.
+    pub async fn receive_tree_fs(
+        &mut self,
+        allowlist: Vec<PathBuf>,
+        out_dir: Option<&Path>,
+    ) -> D4FTResult<()> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                let file_list = self
+                    .file_list
+                    .take()
+                    .ok_or(D4FTError::NoFileTransferPrepared)?;
+
+                let allowed = allowlist
+                    .iter()
+                    .map(PathBuf::as_path)
+                    .collect::<HashSet<_>>();
+                let accepted = file_list
+                    .into_iter()
+                    .filter(|item| allowed.contains(item.path()))
+                    .collect::<Vec<_>>();
+
+                if let Some(item) = accepted.iter().find(|item| !is_safe_path(item.path())) {
+                    let path = item.path().to_path_buf();
+                    self.encryptor
+                        .encode(&protocol::FileListResponse::Reject {
+                            reason: format!("unsafe path: {}", path.display()),
+                        })
+                        .await?;
+                    return Err(D4FTError::UnsafePath { path });
+                }
+
+                let out_dir = out_dir.unwrap_or(".".as_ref());
+
+                for item in &accepted {
+                    if let FileListItem::Directory { path } = item {
+                        fs::create_dir_all(out_dir.join(path))
+                            .await
+                            .map_err(|source| D4FTError::FileWriteError { source })?;
+                    }
+                }
+
+                let total_size = accepted.iter().filter_map(FileListItem::size).sum();
+
+                let files = accepted
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        FileListItem::File { path, .. } => Some((path.clone(), out_dir.join(path))),
+                        FileListItem::Directory { .. } => None,
+                    })
+                    .collect();
+
+                self.receive_files(allowlist, files, total_size).await
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    /// Finish an operation run with the cancel token, aborting the connection if it was cancelled.
+    pub(super) async fn settle<R>(&mut self, result: D4FTResult<R>) -> D4FTResult<R> {
+        settle(
+            result,
+            &self.cancel,
+            &self.features,
+            &mut self.encryptor,
+            &mut self.decryptor,
+        )
+        .await
+    }
+
+    /// With sessions, finish the previous transfer and wait for the sender to begin the next one. Returns `false` if the
+    /// sender said goodbye instead. Without sessions, there is always a transfer to receive.
+    pub(super) async fn begin_transfer(&mut self) -> D4FTResult<bool> {
+        if !self.features.supports(Capability::Sessions) {
+            return Ok(true);
+        }
+        if self.goodbye {
+            return Ok(false);
+        }
+
+        // The sender is still waiting for an answer to the previous file list
+        if self.file_list.take().is_some() {
+            self.encryptor
+                .encode(&protocol::FileListResponse::Reject {
+                    reason: "receiver skipped the transfer".to_string(),
+                })
+                .await?;
+        }
+        self.end_transfer().await?;
+
+        match self.decryptor.decode::<protocol::SessionMessage>().await? {
+            protocol::SessionMessage::Begin { transfer } => {
+                self.open_transfer = Some(transfer);
+                Ok(true)
+            }
+            protocol::SessionMessage::Goodbye => {
+                self.goodbye = true;
+                Ok(false)
             }
-            protocol::InitTransfer::Files { files } => Ok(files),
+            message => Err(D4FTError::MalformedMessage {
+                msg: format!("expected a transfer to begin, got {message:?}"),
+            }),
         }
     }
 
-    pub async fn receive_flat_files_fs(
+    /// Wait for the sender to end the open transfer, if there is one.
+    async fn end_transfer(&mut self) -> D4FTResult<()> {
+        let Some(open) = self.open_transfer.take() else {
+            return Ok(());
+        };
+
+        match self.decryptor.decode::<protocol::SessionMessage>().await? {
+            protocol::SessionMessage::End { transfer } if transfer == open => Ok(()),
+            message => Err(D4FTError::MalformedMessage {
+                msg: format!("expected the end of transfer {open}, got {message:?}"),
+            }),
+        }
+    }
+
+    /// Accept the transfer and receive each of the given files, writing them to their mapped destination paths. Files
+    /// with a partial file left over from an interrupted transfer are resumed from where they left off. Files not in
+    /// `files` are read and discarded.
+    async fn receive_files(
         &mut self,
-        mut allowlist: Vec<PathBuf>,
-        out_dir: Option<&Path>,
+        allowlist: Vec<PathBuf>,
+        mut files: BTreeMap<PathBuf, PathBuf>,
+        total_size: u64,
     ) -> D4FTResult<()> {
-        println!("receive_files start");
-        self.accept_files(allowlist.clone()).await?;
+        let resume = if self.features.supports(Capability::Resume) {
+            resume_points(&files).await
+        } else {
+            protocol::Resume::default()
+        };
 
-        allowlist.sort();
+        self.encryptor
+            .encode(&protocol::FileListResponse::Accept {
+                allowlist,
+                resume: resume.clone(),
+            })
+            .await?;
 
-        let out_dir = out_dir.unwrap_or(".".as_ref());
+        self.progress.start(total_size);
 
-        println!("receive_files setup done");
+        // Keep receiving the rest of the files if one of them is corrupted, and report it at the end
+        let mut hash_mismatch = None;
 
-        while !allowlist.is_empty() {
+        while !files.is_empty() {
             let file_header = self.decryptor.decode::<protocol::FileHeader>().await?;
 
-            println!("got a file header: {:?}", &file_header);
-
-            if allowlist.contains(&file_header.path) {
-                println!("receiving file");
-                let handle =
-                    File::create(out_dir.join(file_header.path.file_name().ok_or_else(|| {
-                        D4FTError::CannotReadPath {
-                            path: file_header.path.clone(),
-                        }
-                    })?))
-                    .await
-                    .map_err(|source| D4FTError::FileWriteError { source })?;
-                self.decryptor.decode_file(handle).await?;
+            if let Some(destination) = files.remove(&file_header.path) {
+                self.progress.start_file(
+                    file_header.path.clone(),
+                    file_header.size,
+                    file_header.offset,
+                );
+                let offered = resume.offered(&file_header.path);
+                match self.receive_file(&destination, &file_header, offered).await {
+                    Err(err @ D4FTError::HashMismatch { .. }) => {
+                        hash_mismatch.get_or_insert(err);
+                    }
+                    result => result?,
+                }
             } else {
-                println!("ignoring file");
-                self.decryptor.decode_file(tokio::io::sink()).await?;
+                self.decryptor
+                    .decode_file(tokio::io::sink(), &mut blake3::Hasher::new(), |_| ())
+                    .await?;
+                if file_header.hash.is_some() {
+                    self.decryptor.decode::<protocol::FileTrailer>().await?;
+                }
             }
         }
 
-        Ok(())
+        hash_mismatch.map_or(Ok(()), Err)
     }
 
-    async fn accept_files(&mut self, allowlist: Vec<PathBuf>) -> D4FTResult<()> {
-        self.encryptor
-            .encode(&protocol::FileListResponse::Accept { allowlist })
+    /// Receive a single file, reporting progress as it goes.
+    async fn receive_file(
+        &mut self,
+        destination: &Path,
+        file_header: &protocol::FileHeader,
+        offered: u64,
+    ) -> D4FTResult<()> {
+        receive_file_with(
+            &mut self.decryptor,
+            destination,
+            file_header,
+            offered,
+            |bytes| self.progress.advance(bytes),
+        )
+        .await
+    }
+}
+
+/// Receive a single file into its partial file, starting at the offset in its header, and move it into place once it
+/// is complete. If the file's hash doesn't match, the partial file is deleted. The offset must be 0 or `offered`, the
+/// one this end offered to resume from.
+pub(super) async fn receive_file_with<R: AsyncRead + Unpin>(
+    decryptor: &mut Decryptor<R>,
+    destination: &Path,
+    file_header: &protocol::FileHeader,
+    offered: u64,
+    on_chunk: impl FnMut(u64),
+) -> D4FTResult<()> {
+    let offset = file_header.offset;
+    if offset != 0 && offset != offered {
+        return Err(D4FTError::MalformedMessage {
+            msg: format!(
+                "{} resumed from offset {offset}, which was not offered",
+                file_header.path.display()
+            ),
+        });
+    }
+
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)
             .await
+            .map_err(|source| D4FTError::FileWriteError { source })?;
+    }
+
+    let partial = partial_path(destination);
+    let mut handle = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&partial)
+        .await
+        .map_err(|source| D4FTError::FileOpenError { source })?;
+
+    // Anything past the offset the sender picked can't be trusted, and starting over discards the whole partial file
+    handle
+        .set_len(offset)
+        .await
+        .map_err(|source| D4FTError::FileWriteError { source })?;
+
+    // The hash covers the whole file, including the part received before resuming
+    let mut hasher = blake3::Hasher::new();
+    handle
+        .seek(std::io::SeekFrom::Start(0))
+        .await
+        .map_err(|source| D4FTError::FileReadError { source })?;
+    encoding::hash_prefix(&mut handle, offset, &mut hasher).await?;
+    handle
+        .seek(std::io::SeekFrom::Start(offset))
+        .await
+        .map_err(|source| D4FTError::FileWriteError { source })?;
+
+    decryptor
+        .decode_file(&mut handle, &mut hasher, on_chunk)
+        .await?;
+
+    handle
+        .flush()
+        .await
+        .map_err(|source| D4FTError::FileWriteError { source })?;
+    drop(handle);
+
+    if let Some(algorithm) = &file_header.hash {
+        let trailer = decryptor.decode::<protocol::FileTrailer>().await?;
+        let actual = hasher.finalize().to_hex().to_string();
+
+        // Files hashed with an algorithm we don't know can't be verified
+        if algorithm == protocol::HASH_ALGORITHM && !trailer.hash.eq_ignore_ascii_case(&actual) {
+            fs::remove_file(&partial)
+                .await
+                .map_err(|source| D4FTError::FileWriteError { source })?;
+            return Err(D4FTError::HashMismatch {
+                path: destination.to_path_buf(),
+                expected: trailer.hash,
+                actual,
+            });
+        }
+    }
+
+    fs::rename(&partial, destination)
+        .await
+        .map_err(|source| D4FTError::FileWriteError { source })
+}
+
+/// Find partial files left over from interrupted transfers, returning how much of each file has already been received
+/// along with its hash. `files` maps the paths in the file list to their destinations.
+pub(super) async fn resume_points(files: &BTreeMap<PathBuf, PathBuf>) -> protocol::Resume {
+    let mut resume = protocol::Resume::default();
+    for (path, destination) in files {
+        if let Some((len, hash)) = hash_partial(&partial_path(destination)).await {
+            resume.offsets.insert(path.clone(), len);
+            resume.prefix_hashes.insert(path.clone(), hash);
+        }
+    }
+    resume
+}
+
+/// Hash a partial file, returning its length and hash, or `None` if there isn't one worth resuming.
+async fn hash_partial(partial: &Path) -> Option<(u64, String)> {
+    let handle = fs::File::open(partial).await.ok()?;
+    let metadata = handle.metadata().await.ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return None;
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    encoding::hash_prefix(handle, metadata.len(), &mut hasher)
+        .await
+        .ok()?;
+    Some((metadata.len(), hasher.finalize().to_hex().to_string()))
+}
+
+/// Get the path that a file is written to while it is being received. A file left at this path means that a transfer
+/// was interrupted, and can be resumed from the end of what was written.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_EXTENSION);
+    partial.into()
+}
+
+/// Check that a path received from the sender stays inside the output directory.
+fn is_safe_path(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::send::send_file_with;
+    use crate::test_files::temp_dir;
+    use crate::{init_receive_stream, init_send_stream};
+    use tokio::io::duplex;
+
+    /// Offer a single file at `path`, which the sender's public API can't produce, and accept it as part of a tree.
+    async fn offer_tree_path(path: &str) -> (D4FTResult<()>, D4FTResult<()>) {
+        let out_dir = temp_dir("unsafe");
+        let (a, b) = duplex(64 * 1024);
+
+        let receive = async {
+            let mut receiver =
+                init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+            let allowlist = receiver
+                .receive_file_list()
+                .await?
+                .iter()
+                .map(|item| item.path().to_path_buf())
+                .collect();
+            receiver.receive_tree_fs(allowlist, Some(&out_dir)).await
+        };
+        let send = async {
+            let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+            sender.begin_transfer().await?;
+            sender
+                .prepare_send_files(vec![FileListItem::File {
+                    path: path.into(),
+                    size: 1,
+                }])
+                .await
+                .map(|_| ())
+        };
+
+        tokio::join!(receive, send)
+    }
+
+    #[tokio::test]
+    async fn rejects_parent_path() {
+        let (received, sent) = offer_tree_path("../x").await;
+        assert!(
+            matches!(&received, Err(D4FTError::UnsafePath { path }) if path == Path::new("../x")),
+            "{received:?}"
+        );
+        assert!(matches!(sent, Err(D4FTError::RejectedTransfer { .. })));
+    }
+
+    #[tokio::test]
+    async fn rejects_absolute_path() {
+        let (received, sent) = offer_tree_path("/x").await;
+        assert!(
+            matches!(&received, Err(D4FTError::UnsafePath { path }) if path == Path::new("/x")),
+            "{received:?}"
+        );
+        assert!(matches!(sent, Err(D4FTError::RejectedTransfer { .. })));
+    }
+
+    /// Offer a single ten byte file named `x`, but send it with `header` and `hasher` in place of the ones the sender
+    /// would use, and receive it into a new directory. Returns the receiver's result and that directory.
+    async fn receive_crafted_file(
+        name: &str,
+        header: protocol::FileHeader,
+        hasher: blake3::Hasher,
+    ) -> (D4FTResult<()>, PathBuf) {
+        let source = temp_dir(&format!("{name}-source"));
+        let out_dir = temp_dir(&format!("{name}-out"));
+        std::fs::write(source.join("x"), b"0123456789").unwrap();
+        let (a, b) = duplex(64 * 1024);
+
+        let receive = async {
+            let mut receiver =
+                init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+            let allowlist = receiver
+                .receive_file_list()
+                .await?
+                .iter()
+                .map(|item| item.path().to_path_buf())
+                .collect();
+            receiver
+                .receive_flat_files_fs(allowlist, Some(&out_dir))
+                .await
+        };
+        let send = async {
+            let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+            sender.begin_transfer().await?;
+            sender
+                .prepare_send_files(vec![FileListItem::File {
+                    path: "x".into(),
+                    size: 10,
+                }])
+                .await?;
+            let mut handle = fs::File::open(source.join("x")).await.unwrap();
+            send_file_with(&mut sender.encryptor, &mut handle, header, hasher, |_| ()).await
+        };
+
+        let (received, sent) = tokio::join!(receive, send);
+        sent.unwrap();
+        (received, out_dir)
+    }
+
+    #[tokio::test]
+    async fn rejects_unoffered_offset() {
+        // The receiver has no partial file, so it didn't offer to resume from anywhere
+        let header = protocol::FileHeader {
+            path: "x".into(),
+            size: 10,
+            hash: None,
+            offset: 5,
+        };
+        let (received, out_dir) =
+            receive_crafted_file("unoffered", header, blake3::Hasher::new()).await;
+
+        assert!(
+            matches!(&received, Err(D4FTError::MalformedMessage { msg }) if msg.contains("not offered")),
+            "{received:?}"
+        );
+        assert!(!out_dir.join("x").exists());
+        assert!(!out_dir.join("x.d4ft4-partial").exists());
+    }
+
+    #[tokio::test]
+    async fn deletes_file_with_mismatched_hash() {
+        let header = protocol::FileHeader {
+            path: "x".into(),
+            size: 10,
+            hash: Some(protocol::HASH_ALGORITHM.to_string()),
+            offset: 0,
+        };
+        // Stands in for the file being corrupted on the way
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"corrupted");
+        let (received, out_dir) = receive_crafted_file("mismatch", header, hasher).await;
+
+        assert!(
+            matches!(&received, Err(D4FTError::HashMismatch { path, .. }) if path == &out_dir.join("x")),
+            "{received:?}"
+        );
+        assert!(!out_dir.join("x").exists());
+        assert!(!out_dir.join("x.d4ft4-partial").exists());
     }
 }
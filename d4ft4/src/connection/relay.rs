@@ -0,0 +1,63 @@
+use crate::connection::{
+    handshake_connect, handshake_listen, ConnectionOptions, InitConnection, Receiver, Sender,
+};
+use crate::{encoding, protocol, D4FTError, D4FTResult};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Like [`init_send_with_options`](crate::init_send_with_options), but meet the other end at a relay instead of one
+/// end listening. Both ends connect to the relay at `address` with the same `rendezvous` ID, which should be hard to
+/// guess so that nobody else can take the other end's place; the password still keeps the transfer itself private.
+pub async fn init_send_relay<A: ToSocketAddrs>(
+    address: A,
+    rendezvous: &str,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Sender> {
+    init_relay(address, rendezvous, password, options).await
+}
+
+/// Like [`init_receive_with_options`](crate::init_receive_with_options), but meet the other end at a relay. See
+/// [`init_send_relay`].
+pub async fn init_receive_relay<A: ToSocketAddrs>(
+    address: A,
+    rendezvous: &str,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Receiver> {
+    init_relay(address, rendezvous, password, options).await
+}
+
+async fn init_relay<A: ToSocketAddrs, Conn: InitConnection<TcpStream>>(
+    address: A,
+    rendezvous: &str,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Conn> {
+    let mut socket = TcpStream::connect(address)
+        .await
+        .map_err(|source| D4FTError::SocketError { source })?;
+
+    encoding::encode_plaintext(
+        protocol::RelayRequest {
+            rendezvous: rendezvous.to_string(),
+        },
+        &mut socket,
+    )
+    .await?;
+
+    // This waits until the other end arrives at the relay
+    match encoding::decode_plaintext::<protocol::RelayResponse, _>(
+        &mut socket,
+        options.limits.control,
+    )
+    .await?
+    {
+        protocol::RelayResponse::Paired { listen: true } => {
+            handshake_listen(socket, password, options).await
+        }
+        protocol::RelayResponse::Paired { listen: false } => {
+            handshake_connect(socket, password, options).await
+        }
+        protocol::RelayResponse::Reject { reason } => Err(D4FTError::RejectedHandshake { reason }),
+    }
+}
@@ -1,80 +1,314 @@
-use crate::connection::{Connection, InitConnection};
-use crate::encoding::{Decryptor, Encryptor};
-use crate::{protocol, D4FTError, D4FTResult, FileListItem};
-use std::path::PathBuf;
+use crate::cancel::CancelToken;
+use crate::connection::{settle, Connection, Features, InitConnection, Parts};
+use crate::encoding::{self, Decryptor, Encryptor};
+use crate::progress::{ProgressSink, ProgressTracker};
+use crate::{protocol, Capability, D4FTError, D4FTResult, FileListItem};
+use faccess::PathExt;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use tokio::fs::File;
-use tokio::io::AsyncSeekExt;
-use tokio::net::tcp;
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
 
-pub struct Sender {
-    encryptor: Encryptor<tcp::OwnedWriteHalf>,
-    decryptor: Decryptor<tcp::OwnedReadHalf>,
+pub struct Sender<T = TcpStream> {
+    pub(super) encryptor: Encryptor<WriteHalf<T>>,
+    pub(super) decryptor: Decryptor<ReadHalf<T>>,
+    pub(super) features: Features,
+    progress: ProgressTracker,
+    cancel: CancelToken,
+    /// The number of transfers started so far.
+    transfers: u64,
+    /// Whether the last transfer was answered, but the receiver hasn't been told that it is over yet.
+    open_transfer: bool,
 }
 
-impl Connection for Sender {}
+impl<T> Connection for Sender<T> {
+    fn features(&self) -> &Features {
+        &self.features
+    }
+}
 
-impl InitConnection for Sender {
+impl<T> InitConnection<T> for Sender<T> {
     const IS_SENDER: bool = true;
     fn init(
-        encryptor: Encryptor<tcp::OwnedWriteHalf>,
-        decryptor: Decryptor<tcp::OwnedReadHalf>,
+        encryptor: Encryptor<WriteHalf<T>>,
+        decryptor: Decryptor<ReadHalf<T>>,
+        features: Features,
     ) -> Self {
-        Self {
+        Self::from_parts(Parts {
             encryptor,
             decryptor,
+            features,
+            progress: ProgressTracker::default(),
+            cancel: CancelToken::default(),
+        })
+    }
+}
+
+impl<T> Sender<T> {
+    pub(super) fn from_parts(parts: Parts<T>) -> Self {
+        Self {
+            encryptor: parts.encryptor,
+            decryptor: parts.decryptor,
+            features: parts.features,
+            progress: parts.progress,
+            cancel: parts.cancel,
+            transfers: 0,
+            open_transfer: false,
+        }
+    }
+
+    pub(super) fn into_parts(self) -> Parts<T> {
+        Parts {
+            encryptor: self.encryptor,
+            decryptor: self.decryptor,
+            features: self.features,
+            progress: self.progress,
+            cancel: self.cancel,
         }
     }
 }
 
-impl Sender {
-    pub async fn send_text(&mut self, text: String) -> D4FTResult<()> {
-        self.encryptor
-            .encode(&protocol::InitTransfer::Text { text })
-            .await?;
+impl<T: AsyncRead + AsyncWrite> Sender<T> {
+    /// Set a function to be called with the progress of file transfers after every chunk sent, or remove it with
+    /// `None`.
+    pub fn set_progress_sink(&mut self, sink: Option<ProgressSink>) {
+        self.progress.set_sink(sink);
+    }
+
+    /// Get a token that cancels whatever this connection is doing when it is cancelled, even while it is busy.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
 
-        self.accept_response().await
+    /// Close the connection, and wait for the other end to close it too. If the other end goes away without closing,
+    /// this returns [`D4FTError::Truncated`].
+    pub async fn close(mut self) -> D4FTResult<()> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                if self.features.supports(Capability::Sessions) {
+                    self.send_transfer_end().await?;
+                    self.encryptor
+                        .encode(&protocol::SessionMessage::Goodbye)
+                        .await?;
+                }
+                self.encryptor.close().await?;
+                self.decryptor.expect_close().await
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    /// Send a piece of text. If the other end supports [`Capability::Sessions`], this can be called again, along with
+    /// the other send functions, to carry on sending over the same connection. Otherwise, sending again fails with
+    /// [`D4FTError::UnsupportedCapability`].
+    pub async fn send_text(&mut self, text: String) -> D4FTResult<()> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                self.begin_transfer().await?;
+                let result = async {
+                    self.encryptor
+                        .encode(&protocol::InitTransfer::Text { text })
+                        .await?;
+                    self.accept_response().await
+                }
+                .await;
+                self.end_transfer(result)
+            })
+            .await;
+        self.settle(result).await
     }
 
     /// Send files, without any directory structure. This function will trim file paths down to only the file name.
     pub async fn send_flat_files(&mut self, files: Vec<(PathBuf, &mut File)>) -> D4FTResult<()> {
-        let file_list = futures::future::try_join_all(files.iter().map(|(path, f)| async {
-            Ok(FileListItem::File {
-                path: path
-                    .file_name()
-                    .ok_or_else(|| D4FTError::CannotReadPath { path: path.clone() })
-                    .map(Into::into)?,
-                size: f
-                    .metadata()
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                let file_list =
+                    futures::future::try_join_all(files.iter().map(|(path, f)| async {
+                        Ok(FileListItem::File {
+                            path: path
+                                .file_name()
+                                .ok_or_else(|| D4FTError::CannotReadPath { path: path.clone() })
+                                .map(Into::into)?,
+                            size: f
+                                .metadata()
+                                .await
+                                .map_err(|source| D4FTError::FileOpenError { source })?
+                                .len(),
+                        }) as D4FTResult<FileListItem>
+                    }))
+                    .await?;
+
+                self.begin_transfer().await?;
+                let result = self.send_flat_files_inner(files, file_list).await;
+                self.end_transfer(result)
+            })
+            .await;
+        self.settle(result).await
+    }
+
+    async fn send_flat_files_inner(
+        &mut self,
+        files: Vec<(PathBuf, &mut File)>,
+        file_list: Vec<FileListItem>,
+    ) -> D4FTResult<()> {
+        let (allowlist, resume) = self.prepare_send_files(file_list.clone()).await?;
+        let allowlist = allowlist.into_iter().collect::<HashSet<_>>();
+        self.start_progress(&file_list, &allowlist);
+
+        let hashing = self.features.supports(Capability::Hashing);
+        let (encryptor, progress) = (&mut self.encryptor, &mut self.progress);
+        let sending = async move {
+            for (handle, item) in files
+                .into_iter()
+                .map(|f| f.1)
+                .zip(file_list.into_iter())
+                .filter(|(_, item)| allowlist.contains(item.path()))
+            {
+                if let FileListItem::File { path, size } = item {
+                    let resume = resume.get(&path);
+                    send_file(encryptor, progress, hashing, handle, path, size, resume).await?;
+                }
+            }
+
+            // TODO: Handle missing/corrupted files (optional)
+            Ok(())
+        };
+        watch_for_abort(&mut self.decryptor, sending).await
+    }
+
+    /// Recursively send files and directories from the given paths, preserving the directory structure. Each path is
+    /// sent relative to its parent, so sending `/home/user/photos` creates a `photos` folder on the receiving end.
+    pub async fn send_tree<P: AsRef<Path>>(&mut self, paths: &[P]) -> D4FTResult<()> {
+        let cancel = self.cancel.clone();
+        let result = cancel
+            .run(async {
+                self.features.require(Capability::Folders)?;
+
+                let roots = paths
+                    .iter()
+                    .map(|p| p.as_ref().to_path_buf())
+                    .collect::<Vec<_>>();
+                let tree = tokio::task::spawn_blocking(move || walk_tree(&roots))
                     .await
-                    .map_err(|source| D4FTError::FileOpenError { source })?
-                    .len(),
-            }) as D4FTResult<FileListItem>
-        }))
-        .await?;
-
-        let mut allowlist = self.prepare_send_files(file_list.clone()).await?;
-        allowlist.sort();
-
-        for (handle, item) in files
-            .into_iter()
-            .map(|f| f.1)
-            .zip(file_list.into_iter())
-            .filter(|(_, item)| {
-                allowlist
-                    .binary_search_by_key(&item.path(), |p| p.as_ref())
-                    .is_ok()
+                    .expect("Directory walk task should not panic or be cancelled")?;
+
+                let file_list = tree
+                    .iter()
+                    .map(|(item, _)| item.clone())
+                    .collect::<Vec<_>>();
+
+                self.begin_transfer().await?;
+                let result = self.send_tree_inner(tree, file_list).await;
+                self.end_transfer(result)
             })
-        {
-            if let FileListItem::File { path, size } = item {
-                self.send_file(handle, path, size).await?;
+            .await;
+        self.settle(result).await
+    }
+
+    async fn send_tree_inner(
+        &mut self,
+        tree: Vec<(FileListItem, PathBuf)>,
+        file_list: Vec<FileListItem>,
+    ) -> D4FTResult<()> {
+        let (allowlist, resume) = self.prepare_send_files(file_list.clone()).await?;
+        let allowlist = allowlist.into_iter().collect::<HashSet<_>>();
+        self.start_progress(&file_list, &allowlist);
+
+        let hashing = self.features.supports(Capability::Hashing);
+        let (encryptor, progress) = (&mut self.encryptor, &mut self.progress);
+        let sending = async move {
+            for (item, source) in tree {
+                if !allowlist.contains(item.path()) {
+                    continue;
+                }
+
+                if let FileListItem::File { path, size } = item {
+                    let mut handle = File::open(&source)
+                        .await
+                        .map_err(|source| D4FTError::FileOpenError { source })?;
+                    let resume = resume.get(&path);
+                    send_file(
+                        encryptor,
+                        progress,
+                        hashing,
+                        &mut handle,
+                        path,
+                        size,
+                        resume,
+                    )
+                    .await?;
+                }
             }
+
+            Ok(())
+        };
+        watch_for_abort(&mut self.decryptor, sending).await
+    }
+
+    /// Finish an operation run with the cancel token, aborting the connection if it was cancelled.
+    pub(super) async fn settle<R>(&mut self, result: D4FTResult<R>) -> D4FTResult<R> {
+        settle(
+            result,
+            &self.cancel,
+            &self.features,
+            &mut self.encryptor,
+            &mut self.decryptor,
+        )
+        .await
+    }
+
+    /// With sessions, tell the receiver that another transfer is starting. Without them, the receiver only expects one
+    /// transfer, so any after the first are refused.
+    pub(super) async fn begin_transfer(&mut self) -> D4FTResult<()> {
+        if !self.features.supports(Capability::Sessions) {
+            if self.transfers > 0 {
+                self.features.require(Capability::Sessions)?;
+            }
+            self.transfers += 1;
+            return Ok(());
         }
 
-        // TODO: Handle missing/corrupted files (optional)
+        self.send_transfer_end().await?;
+        self.transfers += 1;
+        self.encryptor
+            .encode(&protocol::SessionMessage::Begin {
+                transfer: self.transfers,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Mark the current transfer as over once it has been answered. The receiver is only told when the next transfer
+    /// begins or the connection is closed, so a receiver that hangs up after one transfer doesn't cause an error. Any
+    /// other error leaves the connection in an unknown state, so the transfer is left as it is.
+    pub(super) fn end_transfer(&mut self, result: D4FTResult<()>) -> D4FTResult<()> {
+        self.open_transfer = self.features.supports(Capability::Sessions)
+            && matches!(result, Ok(()) | Err(D4FTError::RejectedTransfer { .. }));
+        result
+    }
+
+    async fn send_transfer_end(&mut self) -> D4FTResult<()> {
+        if self.open_transfer {
+            self.open_transfer = false;
+            self.encryptor
+                .encode(&protocol::SessionMessage::End {
+                    transfer: self.transfers,
+                })
+                .await?;
+        }
         Ok(())
     }
 
-    async fn prepare_send_files(&mut self, files: Vec<FileListItem>) -> D4FTResult<Vec<PathBuf>> {
+    /// Send the file list, returning the allowlist and what the receiver already has of partially received files.
+    pub(super) async fn prepare_send_files(
+        &mut self,
+        files: Vec<FileListItem>,
+    ) -> D4FTResult<(Vec<PathBuf>, protocol::Resume)> {
         self.encryptor
             .encode(&protocol::InitTransfer::Files { files })
             .await?;
@@ -85,30 +319,25 @@ impl Sender {
             .await?;
 
         match response {
-            protocol::FileListResponse::Accept { allowlist } => Ok(allowlist),
+            protocol::FileListResponse::Accept { allowlist, resume } => Ok((allowlist, resume)),
             protocol::FileListResponse::Reject { reason } => {
                 Err(D4FTError::RejectedTransfer { reason })
             }
         }
     }
 
-    async fn send_file(&mut self, handle: &mut File, path: PathBuf, size: u64) -> D4FTResult<()> {
-        self.encryptor
-            .encode(&protocol::FileHeader {
-                path,
-                size,
-                hash: None,
-            })
-            .await?;
-
-        handle
-            .seek(std::io::SeekFrom::Start(0))
-            .await
-            .map_err(|source| D4FTError::FileReadError { source })?;
-        self.encryptor.encode_file(handle).await
+    /// Start tracking progress for the allowlisted files from a file list.
+    fn start_progress(&mut self, files: &[FileListItem], allowlist: &HashSet<PathBuf>) {
+        self.progress.start(
+            files
+                .iter()
+                .filter(|item| allowlist.contains(item.path()))
+                .filter_map(FileListItem::size)
+                .sum(),
+        );
     }
 
-    async fn accept_response(&mut self) -> D4FTResult<()> {
+    pub(super) async fn accept_response(&mut self) -> D4FTResult<()> {
         let response = self.decryptor.decode::<protocol::Response>().await?;
 
         match response {
@@ -117,3 +346,168 @@ impl Sender {
         }
     }
 }
+
+/// Send a single file, resuming from where the receiver left off if it already has part of it.
+async fn send_file<W: AsyncWrite + Unpin>(
+    encryptor: &mut Encryptor<W>,
+    progress: &mut ProgressTracker,
+    hashing: bool,
+    handle: &mut File,
+    path: PathBuf,
+    size: u64,
+    resume: Option<(u64, &str)>,
+) -> D4FTResult<()> {
+    let (offset, hasher) = resume_point(handle, size, resume).await?;
+    progress.start_file(path.clone(), size, offset);
+
+    send_file_with(
+        encryptor,
+        handle,
+        protocol::FileHeader {
+            path,
+            size,
+            hash: hashing.then(|| protocol::HASH_ALGORITHM.to_string()),
+            offset,
+        },
+        hasher,
+        |bytes| progress.advance(bytes),
+    )
+    .await
+}
+
+/// Work out where to send a file from, given the receiver's offset and the hash of what it has before it. The file is
+/// only resumed if that matches the start of this file, otherwise the receiver's partial file is of a different file
+/// and it is sent from the start. Also returns a hasher that has been fed everything before the offset.
+pub(super) async fn resume_point(
+    handle: &mut File,
+    size: u64,
+    resume: Option<(u64, &str)>,
+) -> D4FTResult<(u64, blake3::Hasher)> {
+    let mut hasher = blake3::Hasher::new();
+    let Some((offset, expected)) = resume.filter(|&(offset, _)| offset > 0 && offset <= size)
+    else {
+        return Ok((0, hasher));
+    };
+
+    handle
+        .seek(std::io::SeekFrom::Start(0))
+        .await
+        .map_err(|source| D4FTError::FileReadError { source })?;
+    encoding::hash_prefix(&mut *handle, offset, &mut hasher).await?;
+
+    if hasher.finalize().to_hex().eq_ignore_ascii_case(expected) {
+        Ok((offset, hasher))
+    } else {
+        Ok((0, blake3::Hasher::new()))
+    }
+}
+
+/// Send file data while watching for the receiver aborting the transfer. The receiver sends nothing else until all of
+/// the files have been sent, so an abort would otherwise go unnoticed until the end.
+async fn watch_for_abort<R: AsyncRead + Unpin>(
+    decryptor: &mut Decryptor<R>,
+    sending: impl Future<Output = D4FTResult<()>>,
+) -> D4FTResult<()> {
+    tokio::select! {
+        biased;
+        result = sending => result,
+        err = decryptor.expect_abort() => Err(err),
+    }
+}
+
+/// Send a file's header, its data from the offset in the header, and its hash trailer if the header has a hash. The hash
+/// covers the whole file, including the part the receiver already has, so `hasher` must have been fed everything before
+/// the offset by [`resume_point`].
+pub(super) async fn send_file_with<W: AsyncWrite + Unpin>(
+    encryptor: &mut Encryptor<W>,
+    handle: &mut File,
+    header: protocol::FileHeader,
+    mut hasher: blake3::Hasher,
+    on_chunk: impl FnMut(u64),
+) -> D4FTResult<()> {
+    let offset = header.offset;
+    let hashing = header.hash.is_some();
+    encryptor.encode(&header).await?;
+
+    handle
+        .seek(std::io::SeekFrom::Start(offset))
+        .await
+        .map_err(|source| D4FTError::FileReadError { source })?;
+    encryptor.encode_file(handle, &mut hasher, on_chunk).await?;
+
+    if hashing {
+        encryptor
+            .encode(&protocol::FileTrailer {
+                hash: hasher.finalize().to_hex().to_string(),
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Walk each root path, returning the file list to send along with the local path of each item. Paths in the file list
+/// are relative to the parent of the root they were found under.
+fn walk_tree(roots: &[PathBuf]) -> D4FTResult<Vec<(FileListItem, PathBuf)>> {
+    let mut tree = Vec::new();
+
+    for root in roots {
+        let root = root
+            .canonicalize()
+            .map_err(|source| D4FTError::WalkDirError {
+                source,
+                path: Some(root.clone()),
+            })?;
+        let base = root.parent().unwrap_or(&root).to_path_buf();
+
+        for node in walkdir::WalkDir::new(&root)
+            .follow_links(true)
+            .follow_root_links(true)
+        {
+            let node = node.map_err(|err| D4FTError::WalkDirError {
+                path: err.path().map(ToOwned::to_owned),
+                source: err.into(),
+            })?;
+            let path = node.path();
+
+            // Pipes, sockets and devices can't be sent, and mustn't turn into empty directories on the other end
+            let file_type = node.file_type();
+            if !file_type.is_file() && !file_type.is_dir() {
+                continue;
+            }
+
+            if !path.readable() {
+                return Err(D4FTError::CannotReadPath {
+                    path: path.to_path_buf(),
+                });
+            }
+
+            let relative_path = path
+                .strip_prefix(&base)
+                .expect("Walked paths should be inside their root")
+                .to_path_buf();
+            if relative_path.as_os_str().is_empty() {
+                continue;
+            }
+
+            tree.push((
+                if file_type.is_file() {
+                    FileListItem::File {
+                        path: relative_path,
+                        size: node
+                            .metadata()
+                            .map_err(|err| D4FTError::FileReadError { source: err.into() })?
+                            .len(),
+                    }
+                } else {
+                    FileListItem::Directory {
+                        path: relative_path,
+                    }
+                },
+                path.to_path_buf(),
+            ));
+        }
+    }
+
+    Ok(tree)
+}
@@ -0,0 +1,167 @@
+use crate::connection::{
+    handshake_listen, init_peer_stream, ConnectionOptions, Listener, Peer, Receiver, Sender,
+};
+use crate::{D4FTError, D4FTResult};
+use futures::Stream;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// The number of clients a [`Server`] handles at once by default.
+const DEFAULT_MAX_CONCURRENT: usize = 16;
+
+/// How long a client has to finish the handshake by default.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Keeps listening for clients, taking the listening side of the handshake with each of them, instead of accepting a
+/// single connection like [`init_send`](crate::init_send) and friends. Every client must use the same password.
+pub struct Server {
+    listener: Listener,
+    password: String,
+    options: ConnectionOptions,
+    max_concurrent: usize,
+    handshake_timeout: Duration,
+}
+
+impl Server {
+    /// Start listening on every address that `address` resolves to, like [`Listener::bind`].
+    pub async fn bind<A: ToSocketAddrs>(address: A, password: String) -> D4FTResult<Self> {
+        Ok(Self::from_listener(
+            Listener::bind(address).await?,
+            password,
+        ))
+    }
+
+    /// Serve clients on a listener that is already bound, either a [`Listener`] or a single
+    /// [`TcpListener`](tokio::net::TcpListener).
+    pub fn from_listener(listener: impl Into<Listener>, password: String) -> Self {
+        Self {
+            listener: listener.into(),
+            password,
+            options: ConnectionOptions::default(),
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// The addresses being listened on, with the actual port when port 0 was asked for.
+    pub fn local_addrs(&self) -> D4FTResult<Vec<SocketAddr>> {
+        self.listener.local_addrs()
+    }
+
+    /// Set the options used for every connection.
+    pub fn options(mut self, options: ConnectionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Set how many clients can be handshaking, or waiting to be taken from the stream, at once. Further clients wait
+    /// in the listen backlog until one of them is done, so a flood of connections can't make the server run the KDF
+    /// for all of them at once. Must be at least 1.
+    pub fn max_concurrent(mut self, max_concurrent: usize) -> Self {
+        assert!(max_concurrent > 0, "Server must allow at least one client");
+        self.max_concurrent = max_concurrent;
+        self
+    }
+
+    /// Set how long a client has to finish the handshake before it is dropped with [`D4FTError::TimedOut`], so clients
+    /// that connect and then say nothing can't hold on to their place in [`Server::max_concurrent`].
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Accept clients that receive, yielding a [`Sender`] for each.
+    pub fn senders(self) -> Incoming<Sender> {
+        self.incoming(handshake_listen)
+    }
+
+    /// Accept clients that send, yielding a [`Receiver`] for each.
+    pub fn receivers(self) -> Incoming<Receiver> {
+        self.incoming(handshake_listen)
+    }
+
+    /// Accept duplex peers, yielding a [`Peer`] for each.
+    pub fn peers(self) -> Incoming<Peer> {
+        self.incoming(|stream, password, options| init_peer_stream(stream, true, password, options))
+    }
+
+    fn incoming<Conn, F, Fut>(self, handshake: F) -> Incoming<Conn>
+    where
+        Conn: Send + 'static,
+        F: Fn(TcpStream, String, ConnectionOptions) -> Fut + Send + 'static,
+        Fut: Future<Output = D4FTResult<Conn>> + Send + 'static,
+    {
+        let (results, receiver) = mpsc::channel(1);
+        let permits = Arc::new(Semaphore::new(self.max_concurrent));
+
+        let task = tokio::spawn(async move {
+            // Dropped along with this task, which aborts handshakes still in progress
+            let mut handshakes = JoinSet::new();
+            loop {
+                let accepted = async {
+                    let permit = permits
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("Server semaphore should never be closed");
+                    (self.listener.accept_next().await, permit)
+                };
+
+                tokio::select! {
+                    Some(_) = handshakes.join_next(), if !handshakes.is_empty() => {}
+                    _ = results.closed() => return,
+                    (accepted, permit) = accepted => match accepted {
+                        Ok(stream) => {
+                            let results = results.clone();
+                            let handshake = tokio::time::timeout(
+                                self.handshake_timeout,
+                                handshake(stream, self.password.clone(), self.options.clone()),
+                            );
+                            handshakes.spawn(async move {
+                                let result = handshake.await.unwrap_or(Err(D4FTError::TimedOut));
+                                // The client counts against the limit until it is taken from the stream
+                                let _ = results.send(result).await;
+                                drop(permit);
+                            });
+                        }
+                        Err(source) => {
+                            let _ = results.send(Err(D4FTError::SocketError { source })).await;
+                        }
+                    },
+                }
+            }
+        });
+
+        Incoming { receiver, task }
+    }
+}
+
+/// A stream of clients that have completed the handshake with a [`Server`]. A client that fails the handshake, such as
+/// one using the wrong password, is yielded as an error, and the stream carries on with the next client. The server
+/// stops listening when this is dropped.
+#[derive(Debug)]
+pub struct Incoming<Conn> {
+    receiver: mpsc::Receiver<D4FTResult<Conn>>,
+    task: JoinHandle<()>,
+}
+
+impl<Conn> Stream for Incoming<Conn> {
+    type Item = D4FTResult<Conn>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl<Conn> Drop for Incoming<Conn> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
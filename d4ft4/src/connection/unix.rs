@@ -0,0 +1,89 @@
+use crate::connection::{
+    handshake_connect, handshake_listen, ConnectionOptions, InitConnection, Receiver, Sender,
+};
+use crate::{D4FTError, D4FTResult};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Like [`init_send_with_options`](crate::init_send_with_options), but over a Unix domain socket at `path`.
+pub async fn init_send_unix<P: AsRef<Path>>(
+    listen: bool,
+    path: P,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Sender<UnixStream>> {
+    if listen {
+        init_listen_unix(path.as_ref(), password, options).await
+    } else {
+        init_connect_unix(path.as_ref(), password, options).await
+    }
+}
+
+/// Like [`init_receive_with_options`](crate::init_receive_with_options), but over a Unix domain socket at `path`.
+pub async fn init_receive_unix<P: AsRef<Path>>(
+    listen: bool,
+    path: P,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Receiver<UnixStream>> {
+    if listen {
+        init_listen_unix(path.as_ref(), password, options).await
+    } else {
+        init_connect_unix(path.as_ref(), password, options).await
+    }
+}
+
+async fn init_listen_unix<Conn: InitConnection<UnixStream>>(
+    path: &Path,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Conn> {
+    remove_stale_socket(path).await?;
+
+    let listener = UnixListener::bind(path).map_err(|source| D4FTError::SocketError { source })?;
+    let accepted = listener.accept().await;
+
+    // Only one connection is accepted, so nothing else can use the socket file
+    drop(listener);
+    let _ = tokio::fs::remove_file(path).await;
+
+    let (socket, _) = accepted.map_err(|source| D4FTError::SocketError { source })?;
+    handshake_listen(socket, password, options).await
+}
+
+async fn init_connect_unix<Conn: InitConnection<UnixStream>>(
+    path: &Path,
+    password: String,
+    options: ConnectionOptions,
+) -> D4FTResult<Conn> {
+    let socket = UnixStream::connect(path)
+        .await
+        .map_err(|source| D4FTError::SocketError { source })?;
+
+    handshake_connect(socket, password, options).await
+}
+
+/// Remove a socket file left behind by a listener that went away without cleaning up, which refuses connections. Any
+/// other socket is left alone, including one we can't connect to for some other reason such as permissions, and binding
+/// to it fails.
+async fn remove_stale_socket(path: &Path) -> D4FTResult<()> {
+    let Ok(metadata) = tokio::fs::symlink_metadata(path).await else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Ok(());
+    }
+
+    let stale = matches!(
+        UnixStream::connect(path).await,
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused
+    );
+    if stale {
+        tokio::fs::remove_file(path)
+            .await
+            .map_err(|source| D4FTError::SocketError { source })?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::protocol::{self, PeerRole, Version};
+use crate::{D4FTError, D4FTResult};
+
+/// The UDP port that beacons are broadcast to by default.
+pub const DISCOVERY_PORT: u16 = 47474;
+
+/// The largest beacon that will be sent or read. Beacons have to fit in a single datagram.
+const MAX_BEACON_SIZE: usize = 1024;
+
+/// Finds peers on the local network, and lets a listening peer be found. Listening peers broadcast a small plaintext
+/// beacon with their name, role and port, which anyone browsing on the same network picks up. Beacons are not
+/// authenticated, so they are only a hint for where to connect; the password still protects the connection itself.
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    address: SocketAddr,
+    interval: Duration,
+    expiry: Duration,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            address: (Ipv4Addr::BROADCAST, DISCOVERY_PORT).into(),
+            interval: Duration::from_secs(1),
+            expiry: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Discovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send beacons to `address` instead of broadcasting them, and browse for them on the port of `address`.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Set how often beacons are sent while advertising.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set how long a browser remembers a peer after its last beacon. A peer that is heard from again after this is
+    /// yielded again, as if it were new.
+    pub fn expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Start advertising a peer listening on `port`, until the returned [`Advertisement`] is dropped. Must be called
+    /// from within a tokio runtime.
+    pub async fn advertise(
+        &self,
+        name: impl Into<String>,
+        role: PeerRole,
+        port: u16,
+    ) -> D4FTResult<Advertisement> {
+        let beacon = serde_json::to_vec(&protocol::Beacon {
+            service: protocol::BEACON_SERVICE.to_string(),
+            version: protocol::PROTOCOL_VERSION,
+            name: name.into(),
+            role,
+            port,
+        })
+        .map_err(|source| D4FTError::JsonEncodeError { source })?;
+        if beacon.len() > MAX_BEACON_SIZE {
+            return Err(D4FTError::MessageTooLarge {
+                size: beacon.len() as u64,
+                limit: MAX_BEACON_SIZE as u64,
+            });
+        }
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .await
+            .map_err(|source| D4FTError::SocketError { source })?;
+        socket
+            .set_broadcast(true)
+            .map_err(|source| D4FTError::SocketError { source })?;
+        // Send the first beacon up front, so problems like an unreachable network are reported to the caller
+        socket
+            .send_to(&beacon, self.address)
+            .await
+            .map_err(|source| D4FTError::SocketError { source })?;
+
+        let (address, period) = (self.address, self.interval);
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                // Beacons are best-effort, a lost one is made up for by the next
+                let _ = socket.send_to(&beacon, address).await;
+            }
+        });
+
+        Ok(Advertisement { task })
+    }
+
+    /// Listen for beacons, yielding each peer the first time it is seen, and again if it comes back after going quiet for
+    /// longer than the [expiry](Self::expiry). Only one browser can listen on a port at a time on the same machine.
+    pub async fn browse(&self) -> D4FTResult<impl Stream<Item = D4FTResult<DiscoveredPeer>>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, self.address.port()))
+            .await
+            .map_err(|source| D4FTError::SocketError { source })?;
+
+        Ok(futures::stream::try_unfold(
+            (socket, HashMap::new(), self.expiry),
+            |(socket, mut last_seen, expiry)| async move {
+                let mut buffer = [0; MAX_BEACON_SIZE];
+                loop {
+                    let (len, source) = socket
+                        .recv_from(&mut buffer)
+                        .await
+                        .map_err(|source| D4FTError::SocketError { source })?;
+
+                    // Anything else sent to the discovery port is ignored
+                    let Ok(beacon) = serde_json::from_slice::<protocol::Beacon>(&buffer[..len])
+                    else {
+                        continue;
+                    };
+                    if beacon.service != protocol::BEACON_SERVICE {
+                        continue;
+                    }
+
+                    let peer = DiscoveredPeer {
+                        name: beacon.name,
+                        role: beacon.role,
+                        address: SocketAddr::new(source.ip(), beacon.port),
+                        version: beacon.version,
+                    };
+                    // Forget peers that have gone quiet, so the map only holds ones heard from recently
+                    let now = Instant::now();
+                    last_seen.retain(|_, seen: &mut Instant| now.duration_since(*seen) <= expiry);
+                    let key = (peer.address, peer.name.clone(), peer.role);
+                    if last_seen.insert(key, now).is_none() {
+                        return Ok(Some((peer, (socket, last_seen, expiry))));
+                    }
+                }
+            },
+        ))
+    }
+}
+
+/// A peer found on the local network.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiscoveredPeer {
+    /// The device name the peer advertised itself with.
+    pub name: String,
+    pub role: PeerRole,
+    /// The address to connect to the peer on.
+    pub address: SocketAddr,
+    pub version: Version,
+}
+
+/// A running advertisement. Beacons stop being sent when this is dropped.
+#[derive(Debug)]
+pub struct Advertisement {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
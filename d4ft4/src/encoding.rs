@@ -1,16 +1,48 @@
 use aead::rand_core::{RngCore, SeedableRng};
+use hmac::Mac;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::compression;
 use crate::error::{D4FTError, D4FTResult};
+use crate::protocol::{self, Compression, Kdf};
 
 const POLY1305_MAC_LENGTH: u64 = 16;
 const FILE_CHUNK_SIZE: usize = 1024 * 1024 * 4;
 
+/// Limits on the size of messages received, checked against the length in each message header before anything is
+/// allocated for it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    /// Largest handshake or control message, such as a file list.
+    pub(crate) control: u64,
+    /// Largest chunk of file data.
+    pub(crate) chunk: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            control: 1024 * 1024 * 16,
+            // Leave room for peers that send bigger chunks than we do
+            chunk: FILE_CHUNK_SIZE as u64 * 2,
+        }
+    }
+}
+
+fn check_size(size: u64, limit: u64) -> D4FTResult<()> {
+    if size > limit {
+        Err(D4FTError::MessageTooLarge { size, limit })
+    } else {
+        Ok(())
+    }
+}
+
+/// Write an unencrypted message, returning the bytes written so they can be authenticated once a key is agreed.
 pub(crate) async fn encode_plaintext<T: Serialize, W: AsyncWriteExt + Unpin>(
     data: T,
     mut writer: W,
-) -> D4FTResult<()> {
+) -> D4FTResult<Vec<u8>> {
     let mut bytes = b"D4FT\0\0\0\0\0\0\0\0".to_vec();
 
     data.serialize(&mut serde_json::Serializer::new(&mut bytes))
@@ -22,12 +54,25 @@ pub(crate) async fn encode_plaintext<T: Serialize, W: AsyncWriteExt + Unpin>(
     writer
         .write_all(&bytes)
         .await
-        .map_err(|source| D4FTError::EncodeWriteError { source })
+        .map_err(|source| D4FTError::EncodeWriteError { source })?;
+
+    Ok(bytes)
 }
 
 pub(crate) async fn decode_plaintext<T: DeserializeOwned, R: AsyncReadExt + Unpin>(
-    mut reader: R,
+    reader: R,
+    max_size: u64,
 ) -> D4FTResult<T> {
+    decode_plaintext_with_bytes(reader, max_size)
+        .await
+        .map(|(data, _)| data)
+}
+
+/// Read an unencrypted message, also returning the bytes read so they can be authenticated once a key is agreed.
+pub(crate) async fn decode_plaintext_with_bytes<T: DeserializeOwned, R: AsyncReadExt + Unpin>(
+    mut reader: R,
+    max_size: u64,
+) -> D4FTResult<(T, Vec<u8>)> {
     let mut tag = [0u8; 4];
     reader
         .read_exact(&mut tag)
@@ -44,7 +89,9 @@ pub(crate) async fn decode_plaintext<T: DeserializeOwned, R: AsyncReadExt + Unpi
         .read_exact(&mut num_bytes)
         .await
         .map_err(|source| D4FTError::DecodeReadError { source })?;
-    let num_bytes = u64::from_be_bytes(num_bytes) as usize;
+    let num_bytes = u64::from_be_bytes(num_bytes);
+    check_size(num_bytes, max_size)?;
+    let num_bytes = num_bytes as usize;
 
     let mut bytes = vec![0u8; num_bytes];
     reader
@@ -52,7 +99,36 @@ pub(crate) async fn decode_plaintext<T: DeserializeOwned, R: AsyncReadExt + Unpi
         .await
         .map_err(|source| D4FTError::DecodeReadError { source })?;
 
-    serde_json::from_slice(&bytes).map_err(|source| D4FTError::JsonDecodeError { source })
+    let data =
+        serde_json::from_slice(&bytes).map_err(|source| D4FTError::JsonDecodeError { source })?;
+
+    let mut raw = [&tag[..], &(num_bytes as u64).to_be_bytes()].concat();
+    raw.extend_from_slice(&bytes);
+    Ok((data, raw))
+}
+
+/// Feed the first `len` bytes of a file into a hasher. Used to hash the part of a file that was already transferred
+/// when resuming.
+pub(crate) async fn hash_prefix<F: AsyncRead + Unpin>(
+    file: F,
+    len: u64,
+    hasher: &mut blake3::Hasher,
+) -> D4FTResult<()> {
+    let mut file = file.take(len);
+    let mut bytes = vec![0u8; FILE_CHUNK_SIZE];
+
+    loop {
+        let num_bytes = file
+            .read(&mut bytes)
+            .await
+            .map_err(|source| D4FTError::FileReadError { source })?;
+
+        if num_bytes == 0 {
+            return Ok(());
+        }
+
+        hasher.update(&bytes[..num_bytes]);
+    }
 }
 
 pub(crate) struct InitializationVectors {
@@ -60,10 +136,11 @@ pub(crate) struct InitializationVectors {
     pub(crate) client_server_salt: [u8; 32],
     pub(crate) server_client_nonce: [u8; 19],
     pub(crate) server_client_salt: [u8; 32],
+    pub(crate) kdf: Kdf,
 }
 
 impl InitializationVectors {
-    pub(crate) fn generate() -> Self {
+    pub(crate) fn generate(kdf: Kdf) -> Self {
         let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
 
         let mut ivs = InitializationVectors {
@@ -71,6 +148,7 @@ impl InitializationVectors {
             client_server_salt: [0u8; 32],
             server_client_nonce: [0u8; 19],
             server_client_salt: [0u8; 32],
+            kdf,
         };
 
         rng.fill_bytes(&mut ivs.client_server_nonce);
@@ -87,6 +165,7 @@ impl InitializationVectors {
             client_server_salt: [0u8; 32],
             server_client_nonce: [0u8; 19],
             server_client_salt: [0u8; 32],
+            kdf: vars.kdf,
         };
 
         hex::decode_to_slice(vars.client_server_nonce, &mut ivs.client_server_nonce)
@@ -101,51 +180,216 @@ impl InitializationVectors {
         Ok(ivs)
     }
 
+    /// Bytes that identify this session, used to bind the key exchange to it.
+    pub(crate) fn session_id(&self) -> Vec<u8> {
+        [
+            &self.client_server_nonce[..],
+            &self.client_server_salt,
+            &self.server_client_nonce,
+            &self.server_client_salt,
+        ]
+        .concat()
+    }
+
     pub(crate) fn to_protocol(&self) -> crate::protocol::EncryptionVars {
         crate::protocol::EncryptionVars {
             client_server_nonce: hex::encode_upper(self.client_server_nonce),
             client_server_salt: hex::encode_upper(self.client_server_salt),
             server_client_nonce: hex::encode_upper(self.server_client_nonce),
             server_client_salt: hex::encode_upper(self.server_client_salt),
+            kdf: self.kdf,
+        }
+    }
+}
+
+/// Combine the password-authenticated secret from the PAKE with the ephemeral Diffie-Hellman secret. Since the ephemeral
+/// keys are thrown away after the handshake, a leaked password doesn't expose past sessions.
+pub(crate) fn combine_secrets(pake_secret: &[u8], dh_secret: &[u8], session_id: &[u8]) -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(Some(session_id), &[pake_secret, dh_secret].concat())
+        .expand(b"d4ft4 session secret", &mut secret)
+        .expect("HKDF should not error on a hardcoded output length");
+    secret
+}
+
+/// Directional keys and nonces for a session. The nonces in the handshake only feed into the session ID, and the ones
+/// used for encryption are expanded from the session key along with the keys.
+pub(crate) struct SessionKeys {
+    pub(crate) client_server_key: [u8; 32],
+    pub(crate) client_server_nonce: [u8; 19],
+    pub(crate) server_client_key: [u8; 32],
+    pub(crate) server_client_nonce: [u8; 19],
+    /// Secret for keying extra streams from the sending end, see [`stream_keys`].
+    pub(crate) stream_secret: [u8; 32],
+}
+
+impl SessionKeys {
+    /// Expand the session secret into a key and nonce for each direction. The secret is already uniformly random, so
+    /// it doesn't need to go through a slow KDF, that is run on the password instead by [`harden_password`].
+    pub(crate) fn derive(secret: &[u8; 32], ivs: &InitializationVectors) -> Self {
+        let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(Some(&ivs.session_id()), secret);
+
+        let mut keys = SessionKeys {
+            client_server_key: [0u8; 32],
+            client_server_nonce: [0u8; 19],
+            server_client_key: [0u8; 32],
+            server_client_nonce: [0u8; 19],
+            stream_secret: [0u8; 32],
+        };
+
+        for (info, output) in [
+            (
+                &b"d4ft4 client-server key"[..],
+                &mut keys.client_server_key[..],
+            ),
+            (b"d4ft4 client-server nonce", &mut keys.client_server_nonce),
+            (b"d4ft4 server-client key", &mut keys.server_client_key),
+            (b"d4ft4 server-client nonce", &mut keys.server_client_nonce),
+            (b"d4ft4 stream secret", &mut keys.stream_secret),
+        ] {
+            hkdf.expand(info, output)
+                .expect("HKDF should not error on hardcoded output lengths");
         }
+
+        keys
     }
 }
 
-async fn derive_key(password: String, salt: [u8; 32]) -> [u8; 32] {
+/// Get the key and nonce for an extra stream, such as a QUIC file stream. Each stream on a connection must get its own
+/// index, across all of its transfers, so that no two streams share a key.
+#[cfg(feature = "quic")]
+pub(crate) fn stream_keys(stream_secret: &[u8; 32], index: u64) -> ([u8; 32], [u8; 19]) {
+    let hkdf = hkdf::Hkdf::<sha2::Sha256>::from_prk(stream_secret)
+        .expect("HKDF should accept a 32 byte PRK");
+    let (mut key, mut nonce) = ([0u8; 32], [0u8; 19]);
+    hkdf.expand_multi_info(&[b"d4ft4 stream key", &index.to_be_bytes()], &mut key)
+        .expect("HKDF should not error on hardcoded output lengths");
+    hkdf.expand_multi_info(&[b"d4ft4 stream nonce", &index.to_be_bytes()], &mut nonce)
+        .expect("HKDF should not error on hardcoded output lengths");
+    (key, nonce)
+}
+
+pub(crate) const CONFIRM_LISTENER: &[u8] = b"d4ft4 confirm listener";
+pub(crate) const CONFIRM_CONNECTOR: &[u8] = b"d4ft4 confirm connector";
+pub(crate) const HANDSHAKE_LISTENER: &[u8] = b"d4ft4 handshake listener";
+pub(crate) const HANDSHAKE_CONNECTOR: &[u8] = b"d4ft4 handshake connector";
+
+/// Compute a tag over part of the handshake, keyed with the session secret. Key confirmation tags prove that an end
+/// derived the same secret without revealing it, so a mismatch means the passwords were different. Handshake tags
+/// prove that both ends saw the same plaintext handshake. The label keeps the tags for each purpose and end distinct.
+pub(crate) fn transcript_tag(secret: &[u8; 32], label: &[u8], transcript: &[u8]) -> Vec<u8> {
+    transcript_mac(secret, label, transcript)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+pub(crate) fn verify_transcript_tag(
+    secret: &[u8; 32],
+    label: &[u8],
+    transcript: &[u8],
+    tag: &[u8],
+) -> bool {
+    transcript_mac(secret, label, transcript)
+        .verify_slice(tag)
+        .is_ok()
+}
+
+fn transcript_mac(secret: &[u8; 32], label: &[u8], transcript: &[u8]) -> hmac::Hmac<sha2::Sha256> {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret)
+        .expect("HMAC should accept any key length");
+    mac.update(label);
+    mac.update(transcript);
+    mac
+}
+
+/// Run the session's memory-hard KDF on the password before it goes into the key exchange, so that each guess at the
+/// password costs an attacker a run of the KDF. The connecting end's salt is used, so both ends get the same result.
+pub(crate) async fn harden_password(
+    password: &str,
+    ivs: &InitializationVectors,
+) -> D4FTResult<[u8; 32]> {
+    derive_key(
+        password.as_bytes().to_vec(),
+        ivs.client_server_salt,
+        ivs.kdf,
+    )
+    .await
+}
+
+async fn derive_key(secret: Vec<u8>, salt: [u8; 32], kdf: Kdf) -> D4FTResult<[u8; 32]> {
     tokio::task::spawn_blocking(move || {
         let mut key = [69u8; 32];
-        println!("starting key derive");
-        scrypt::scrypt(
-            password.as_bytes(),
-            &salt,
-            &scrypt::Params::new(16, 8, 1, 32)
-                .expect("Scrypt should not error on hardcoded params"),
-            &mut key,
-        )
-        .expect("Scrypt should not error on hardcoded output length");
-        println!("key derive done");
-        key
+        match kdf {
+            Kdf::Scrypt { log_n, r, p } => scrypt::scrypt(
+                &secret,
+                &salt,
+                &scrypt::Params::new(log_n, r, p, 32).map_err(|_| D4FTError::InvalidKdf { kdf })?,
+                &mut key,
+            )
+            .expect("Scrypt should not error on hardcoded output length"),
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => argon2::Argon2::new(
+                argon2::Algorithm::Argon2id,
+                argon2::Version::V0x13,
+                argon2::Params::new(memory_kib, iterations, parallelism, Some(32))
+                    .map_err(|_| D4FTError::InvalidKdf { kdf })?,
+            )
+            .hash_password_into(&secret, &salt, &mut key)
+            .map_err(|_| D4FTError::InvalidKdf { kdf })?,
+        }
+        Ok(key)
     })
     .await
-    .expect("Key derive task should not panic on hardcoded params and should not be cancelled")
+    .expect("Key derive task should not panic and should not be cancelled")
 }
 
-pub(crate) struct Encryptor<W: AsyncWrite + Unpin> {
-    encryptor: aead::stream::EncryptorBE32<chacha20poly1305::XChaCha20Poly1305>,
+/// Header tag of the frame that closes a session, sealed with the STREAM last block flag. A session that ends without
+/// it may have been truncated.
+const CLOSE_TAG: &[u8; 4] = b"D4FC";
+
+/// Header tag of the frame that aborts a session with a [`protocol::Abort`], sealed like the close frame.
+const ABORT_TAG: &[u8; 4] = b"D4FA";
+
+pub(crate) struct Encryptor<W> {
+    /// `None` once the session has been closed or aborted.
+    encryptor: Option<aead::stream::EncryptorBE32<chacha20poly1305::XChaCha20Poly1305>>,
     writer: W,
+    compression: Option<Compression>,
+    /// The frame being written, and how much of it has been written. If sending it is cancelled part way through, the
+    /// rest is written before an abort frame, so that the other end can still read the abort.
+    unsent: Vec<u8>,
+    written: usize,
+    /// Whether writing a frame failed part way through. Nothing else can be sent after that, since the other end would
+    /// read it as part of the broken frame.
+    torn: bool,
 }
 
 impl<W: AsyncWrite + Unpin> Encryptor<W> {
-    pub(crate) async fn new(password: String, salt: [u8; 32], nonce: &[u8; 19], writer: W) -> Self {
+    pub(crate) fn new(key: &[u8; 32], nonce: &[u8; 19], writer: W) -> Self {
         Self {
-            encryptor: aead::stream::EncryptorBE32::new(
-                &derive_key(password, salt).await.into(),
-                nonce.into(),
-            ),
+            encryptor: Some(aead::stream::EncryptorBE32::new(key.into(), nonce.into())),
             writer,
+            compression: None,
+            unsent: Vec::new(),
+            written: 0,
+            torn: false,
         }
     }
 
+    /// Compress all messages sent after this with the given codec.
+    pub(crate) fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    #[cfg(feature = "quic")]
+    pub(crate) fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
     pub(crate) async fn encode<T: Serialize>(&mut self, data: &T) -> D4FTResult<()> {
         self.encode_data(
             serde_json::to_vec(data).map_err(|source| D4FTError::JsonEncodeError { source })?,
@@ -153,10 +397,11 @@ impl<W: AsyncWrite + Unpin> Encryptor<W> {
         .await
     }
 
-    // Could return a hash later
     pub(crate) async fn encode_file<F: AsyncRead + Unpin>(
         &mut self,
         mut file: F,
+        hasher: &mut blake3::Hasher,
+        mut on_chunk: impl FnMut(u64),
     ) -> D4FTResult<()> {
         loop {
             let mut bytes = vec![0u8; FILE_CHUNK_SIZE];
@@ -167,6 +412,7 @@ impl<W: AsyncWrite + Unpin> Encryptor<W> {
                 .map_err(|source| D4FTError::FileReadError { source })?;
 
             bytes.truncate(num_bytes);
+            hasher.update(&bytes);
 
             self.encode_data(bytes).await?;
 
@@ -174,105 +420,302 @@ impl<W: AsyncWrite + Unpin> Encryptor<W> {
             if num_bytes == 0 {
                 return Ok(());
             }
+
+            on_chunk(num_bytes as u64);
         }
     }
 
-    async fn encode_data(&mut self, mut data: Vec<u8>) -> D4FTResult<()> {
-        // Build header
+    /// Send the close frame and shut down the writer. Nothing can be sent after this.
+    pub(crate) async fn close(&mut self) -> D4FTResult<()> {
+        self.encode_last(CLOSE_TAG, Vec::new()).await
+    }
+
+    /// Send an abort frame with the reason the session is being abandoned, and shut down the writer. Nothing can be sent
+    /// after this. A frame that was cancelled part way through is finished first, but if writing one failed, the abort
+    /// frame can't be sent, so the writer is only shut down.
+    pub(crate) async fn abort(&mut self, reason: String) -> D4FTResult<()> {
+        if self.encryptor.is_none() {
+            return Ok(());
+        }
+        if self.torn {
+            return self.shutdown().await;
+        }
+        self.write_unsent().await?;
+
+        let data = serde_json::to_vec(&protocol::Abort { reason })
+            .map_err(|source| D4FTError::JsonEncodeError { source })?;
+        self.encode_last(ABORT_TAG, data).await
+    }
+
+    /// Shut down the writer without sending a close frame, so that the other end sees the session end early. Nothing can
+    /// be sent after this.
+    pub(crate) async fn shutdown(&mut self) -> D4FTResult<()> {
+        if self.encryptor.take().is_none() {
+            return Ok(());
+        }
+        self.writer
+            .shutdown()
+            .await
+            .map_err(|source| D4FTError::EncodeWriteError { source })
+    }
+
+    /// Send the last frame of the session, sealed with the STREAM last block flag, and shut down the writer.
+    async fn encode_last(&mut self, tag: &[u8; 4], mut data: Vec<u8>) -> D4FTResult<()> {
+        let encryptor = self.encryptor.take().ok_or(D4FTError::ConnectionClosed)?;
+
         let mut header = [0u8; 12];
-        header[0..4].copy_from_slice(b"D4FT");
+        header[0..4].copy_from_slice(tag);
         header[4..12].copy_from_slice(&(data.len() as u64 + POLY1305_MAC_LENGTH).to_be_bytes());
 
-        // Encrypt data
-        self.encryptor
-            .encrypt_next_in_place(&header, &mut data)
+        encryptor
+            .encrypt_last_in_place(&header, &mut data)
             .map_err(|source| D4FTError::EncryptionError { source })?;
 
-        // Write header
         self.writer
             .write_all(&header)
             .await
             .map_err(|source| D4FTError::EncodeWriteError { source })?;
-
-        // Write data
         self.writer
             .write_all(&data)
             .await
+            .map_err(|source| D4FTError::EncodeWriteError { source })?;
+        self.writer
+            .shutdown()
+            .await
             .map_err(|source| D4FTError::EncodeWriteError { source })
     }
+
+    async fn encode_data(&mut self, mut data: Vec<u8>) -> D4FTResult<()> {
+        // Finish anything left of a cancelled frame first. The STREAM counter moves on as soon as a frame is
+        // encrypted, so once that happens the frame has to be queued without another chance to cancel.
+        self.write_unsent().await?;
+
+        if let Some(codec) = self.compression {
+            data = compression::compress(codec, &data);
+        }
+
+        // Build header
+        let mut header = [0u8; 12];
+        header[0..4].copy_from_slice(b"D4FT");
+        header[4..12].copy_from_slice(&(data.len() as u64 + POLY1305_MAC_LENGTH).to_be_bytes());
+
+        // Encrypt data
+        self.encryptor
+            .as_mut()
+            .ok_or(D4FTError::ConnectionClosed)?
+            .encrypt_next_in_place(&header, &mut data)
+            .map_err(|source| D4FTError::EncryptionError { source })?;
+
+        // Write header and data
+        self.unsent.extend_from_slice(&header);
+        self.unsent.extend_from_slice(&data);
+        self.write_unsent().await
+    }
+
+    /// Write whatever is left of the current frame. Safe to cancel, since each write either happens or doesn't, and
+    /// whatever is left is kept for next time.
+    async fn write_unsent(&mut self) -> D4FTResult<()> {
+        while self.written < self.unsent.len() {
+            match self.writer.write(&self.unsent[self.written..]).await {
+                Ok(0) => {
+                    self.torn = true;
+                    return Err(D4FTError::EncodeWriteError {
+                        source: std::io::ErrorKind::WriteZero.into(),
+                    });
+                }
+                Ok(written) => self.written += written,
+                Err(source) => {
+                    self.torn = true;
+                    return Err(D4FTError::EncodeWriteError { source });
+                }
+            }
+        }
+
+        self.unsent.clear();
+        self.written = 0;
+        Ok(())
+    }
 }
 
-pub(crate) struct Decryptor<R: AsyncRead + Unpin> {
-    decryptor: aead::stream::DecryptorBE32<chacha20poly1305::XChaCha20Poly1305>,
+/// A message received by a [`Decryptor`].
+enum Frame {
+    Data(Vec<u8>),
+    Close,
+    /// The other end aborted the session, with its reason.
+    Abort(String),
+}
+
+pub(crate) struct Decryptor<R> {
+    /// `None` once the close frame has been received.
+    decryptor: Option<aead::stream::DecryptorBE32<chacha20poly1305::XChaCha20Poly1305>>,
     reader: R,
+    compression: Option<Compression>,
+    limits: Limits,
 }
 
 impl<R: AsyncRead + Unpin> Decryptor<R> {
-    pub(crate) async fn new(password: String, salt: [u8; 32], nonce: &[u8; 19], reader: R) -> Self {
+    pub(crate) fn new(key: &[u8; 32], nonce: &[u8; 19], reader: R, limits: Limits) -> Self {
         Self {
-            decryptor: aead::stream::DecryptorBE32::new(
-                &derive_key(password, salt).await.into(),
-                nonce.into(),
-            ),
+            decryptor: Some(aead::stream::DecryptorBE32::new(key.into(), nonce.into())),
             reader,
+            compression: None,
+            limits,
         }
     }
 
+    /// Decompress all messages received after this with the given codec.
+    pub(crate) fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    #[cfg(feature = "quic")]
+    pub(crate) fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
     pub(crate) async fn decode<T: DeserializeOwned>(&mut self) -> D4FTResult<T> {
-        serde_json::from_slice(&self.decode_data().await?)
+        serde_json::from_slice(&self.decode_data(self.limits.control).await?)
             .map_err(|source| D4FTError::JsonDecodeError { source })
     }
 
-    // Could return a hash later
     pub(crate) async fn decode_file<F: AsyncWrite + Unpin>(
         &mut self,
         mut file: F,
+        hasher: &mut blake3::Hasher,
+        mut on_chunk: impl FnMut(u64),
     ) -> D4FTResult<()> {
         loop {
-            let bytes = self.decode_data().await?;
+            let bytes = self.decode_data(self.limits.chunk).await?;
 
             if bytes.len() == 0 {
                 return Ok(());
             }
 
+            hasher.update(&bytes);
             file.write_all(&bytes)
                 .await
                 .map_err(|source| D4FTError::FileWriteError { source })?;
+
+            on_chunk(bytes.len() as u64);
+        }
+    }
+
+    /// Wait for the other end to close the session.
+    pub(crate) async fn expect_close(&mut self) -> D4FTResult<()> {
+        match self.decode_frame(self.limits.control).await? {
+            Frame::Close => Ok(()),
+            Frame::Data(_) => Err(D4FTError::MalformedMessage {
+                msg: "expected the session to be closed".to_string(),
+            }),
+            Frame::Abort(reason) => Err(D4FTError::Cancelled { reason }),
+        }
+    }
+
+    /// Wait for the other end to abort the session, for when it shouldn't be sending anything else. Returns the error
+    /// to stop with, which is [`D4FTError::Cancelled`] if it did abort.
+    pub(crate) async fn expect_abort(&mut self) -> D4FTError {
+        match self.decode_frame(self.limits.control).await {
+            Ok(Frame::Abort(reason)) => D4FTError::Cancelled { reason },
+            Ok(Frame::Data(_)) => D4FTError::MalformedMessage {
+                msg: "got a message while sending files".to_string(),
+            },
+            Ok(Frame::Close) => D4FTError::ConnectionClosed,
+            Err(err) => err,
+        }
+    }
+
+    /// Read whatever is left of the session, returning the reason if the other end aborted it. Only for after writing to
+    /// the other end has failed, since it reads until the connection ends.
+    pub(crate) async fn abort_reason(&mut self) -> Option<String> {
+        loop {
+            match self.decode_frame(self.limits.chunk).await {
+                Ok(Frame::Data(_)) => continue,
+                Ok(Frame::Abort(reason)) => return Some(reason),
+                Ok(Frame::Close) | Err(_) => return None,
+            }
         }
     }
 
-    async fn decode_data(&mut self) -> D4FTResult<Vec<u8>> {
+    async fn decode_data(&mut self, max_size: u64) -> D4FTResult<Vec<u8>> {
+        match self.decode_frame(max_size).await? {
+            Frame::Data(bytes) => Ok(bytes),
+            Frame::Close => Err(D4FTError::ConnectionClosed),
+            Frame::Abort(reason) => Err(D4FTError::Cancelled { reason }),
+        }
+    }
+
+    /// Read and decrypt the next message, rejecting it if it is larger than `max_size` bytes.
+    async fn decode_frame(&mut self, max_size: u64) -> D4FTResult<Frame> {
+        if self.decryptor.is_none() {
+            return Err(D4FTError::ConnectionClosed);
+        }
+
         // Read header
         let mut header = [0u8; 12];
         self.reader
             .read_exact(&mut header)
             .await
-            .map_err(|source| D4FTError::DecodeReadError { source })?;
+            .map_err(read_error)?;
 
         // Check header tag
-        if header[0..4] != *b"D4FT" {
-            return Err(D4FTError::MalformedMessage {
-                msg: "did not find 'D4FT' header tag".to_string(),
-            });
-        }
+        let is_last = match &header[0..4] {
+            b"D4FT" => false,
+            tag if tag == CLOSE_TAG || tag == ABORT_TAG => true,
+            _ => {
+                return Err(D4FTError::MalformedMessage {
+                    msg: "did not find 'D4FT' header tag".to_string(),
+                })
+            }
+        };
 
         // Decode length
         let mut num_bytes = [0u8; 8];
         num_bytes.copy_from_slice(&header[4..12]);
-        let num_bytes = u64::from_be_bytes(num_bytes) as usize;
+        let num_bytes = u64::from_be_bytes(num_bytes);
+        // Allow for the MAC and compression flag on top of the message itself
+        check_size(num_bytes, max_size.saturating_add(POLY1305_MAC_LENGTH + 1))?;
+        let num_bytes = num_bytes as usize;
 
         // Read data
         let mut bytes = vec![0u8; num_bytes];
         self.reader
             .read_exact(&mut bytes)
             .await
-            .map_err(|source| D4FTError::DecodeReadError { source })?;
+            .map_err(read_error)?;
 
         // Decrypt data
+        if is_last {
+            self.decryptor
+                .take()
+                .expect("Decryptor should be checked before reading")
+                .decrypt_last_in_place(&header, &mut bytes)
+                .map_err(|source| D4FTError::DecryptionError { source })?;
+            if &header[0..4] == ABORT_TAG {
+                let abort = serde_json::from_slice::<protocol::Abort>(&bytes)
+                    .map_err(|source| D4FTError::JsonDecodeError { source })?;
+                return Ok(Frame::Abort(abort.reason));
+            }
+            return Ok(Frame::Close);
+        }
+
         self.decryptor
+            .as_mut()
+            .expect("Decryptor should be checked before reading")
             .decrypt_next_in_place(&header, &mut bytes)
             .map_err(|source| D4FTError::DecryptionError { source })?;
 
-        Ok(bytes)
+        match self.compression {
+            Some(codec) => compression::decompress(codec, bytes, max_size).map(Frame::Data),
+            None => Ok(Frame::Data(bytes)),
+        }
+    }
+}
+
+/// Errors reading from an encrypted session. Running out of data means the session ended without a close frame.
+fn read_error(source: std::io::Error) -> D4FTError {
+    if source.kind() == std::io::ErrorKind::UnexpectedEof {
+        D4FTError::Truncated
+    } else {
+        D4FTError::DecodeReadError { source }
     }
 }
@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-use crate::TransferMode;
+use crate::{Capability, TransferMode, Version};
 
 #[derive(Error, Debug)]
 pub enum D4FTError {
@@ -10,6 +10,12 @@ pub enum D4FTError {
     #[error("rejected transfer: {reason}")]
     RejectedTransfer { reason: String },
 
+    #[error("peer with version {peer_version} does not support {capability:?}")]
+    UnsupportedCapability {
+        capability: Capability,
+        peer_version: Version,
+    },
+
     #[error("incorrect transfer mode")]
     IncorrectTransferMode {
         required: TransferMode,
@@ -34,12 +40,45 @@ pub enum D4FTError {
     #[error("tried to decode a malformed message: {msg}")]
     MalformedMessage { msg: String },
 
+    #[error("QUIC error: {msg}")]
+    QuicError { msg: String },
+
     #[error("socket error")]
     SocketError { source: std::io::Error },
 
     #[error("decryption error")]
     DecryptionError { source: aead::Error },
 
+    #[error("decompression error: {msg}")]
+    DecompressionError { msg: String },
+
+    #[error("message of {size} bytes is larger than the limit of {limit} bytes")]
+    MessageTooLarge { size: u64, limit: u64 },
+
+    #[error("the other end closed the connection")]
+    ConnectionClosed,
+
+    #[error("the connection ended without being closed, data may be missing")]
+    Truncated,
+
+    #[error("cancelled: {reason}")]
+    Cancelled { reason: String },
+
+    #[error("timed out waiting for the other end")]
+    TimedOut,
+
+    #[error("invalid KDF parameters: {kdf:?}")]
+    InvalidKdf { kdf: crate::Kdf },
+
+    #[error("wrong password")]
+    WrongPassword,
+
+    #[error("the handshake was tampered with in transit")]
+    TamperedHandshake,
+
+    #[error("invalid key exchange share")]
+    InvalidKeyShare,
+
     #[error("hex decode error")]
     HexDecodeError { source: hex::FromHexError },
 
@@ -64,12 +103,28 @@ pub enum D4FTError {
     #[error("file transfer not prepared")]
     NoFileTransferPrepared,
 
+    #[error("the other end started a transfer first, which must be received before sending")]
+    TransferPending,
+
     #[error("IO error walking directory at path {path:?}")]
     WalkDirError { source: std::io::Error, path: Option<std::path::PathBuf> },
 
     #[error("path not readable: {path}")]
     CannotReadPath { path: std::path::PathBuf },
 
+    #[error("hash mismatch for received file {path}")]
+    HashMismatch {
+        path: std::path::PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("refusing to write to a path outside of the output directory: {path}")]
+    UnsafePath { path: std::path::PathBuf },
+
+    #[error("more than one file is named {name}")]
+    DuplicateFileName { name: std::path::PathBuf },
+
 }
 
 pub type D4FTResult<T> = Result<T, D4FTError>;
@@ -1,7 +1,16 @@
+mod cancel;
+mod compression;
 mod connection;
+mod discovery;
 mod encoding;
 mod error;
+mod pake;
+mod progress;
 mod protocol;
+mod relay;
+#[cfg(test)]
+#[path = "../tests/common/files.rs"]
+mod test_files;
 
 use std::{
     cmp::Ordering,
@@ -17,9 +26,28 @@ use tokio::{
 
 pub use error::{D4FTError, D4FTResult};
 
-pub use protocol::{FileListItem, TransferMode};
+pub use cancel::CancelToken;
 
-pub use connection::{init_receive, init_send, Connection, Receiver, Sender};
+pub use progress::{Progress, ProgressSink};
+
+pub use protocol::{Capability, FileListItem, Kdf, PeerRole, TransferMode, Version};
+
+pub use discovery::{Advertisement, DiscoveredPeer, Discovery, DISCOVERY_PORT};
+
+pub use connection::{
+    init_peer, init_peer_stream, init_receive, init_receive_relay, init_receive_stream,
+    init_receive_with_options, init_send, init_send_relay, init_send_stream,
+    init_send_with_options, Connection, ConnectionOptions, Features, Incoming, Listener, Peer,
+    Receiver, Sender, Server, Transfer,
+};
+
+pub use relay::{serve_relay, MAX_RENDEZVOUS_LEN};
+
+#[cfg(unix)]
+pub use connection::{init_receive_unix, init_send_unix};
+
+#[cfg(feature = "quic")]
+pub use connection::{init_receive_quic, init_send_quic, QuicReceiver, QuicSender};
 
 // pub struct Connection {
 //     stage: TransferStage,
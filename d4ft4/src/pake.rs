@@ -0,0 +1,155 @@
+//! Password-authenticated key exchange, using SPAKE2 over edwards25519 from the `spake2` crate. A recorded exchange
+//! can't be used to check password guesses offline.
+
+use aead::rand_core::SeedableRng;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+use crate::error::{D4FTError, D4FTResult};
+
+const IDENTITY_CONNECTOR: &[u8] = b"d4ft4 connector";
+const IDENTITY_LISTENER: &[u8] = b"d4ft4 listener";
+
+/// Length of a SPAKE2 message, a side byte followed by a compressed point.
+pub(crate) const SHARE_LENGTH: usize = 33;
+
+/// One end of a SPAKE2 exchange, holding the secret scalar until the peer's share is received.
+pub(crate) struct Pake {
+    state: Spake2<Ed25519Group>,
+    share: [u8; SHARE_LENGTH],
+}
+
+impl Pake {
+    /// Start an exchange bound to the session ID. SPAKE2 is asymmetric, so the end that connects has to take the other
+    /// side from the end that listens.
+    pub(crate) fn start(password: &[u8], session_id: &[u8], is_initiator: bool) -> Self {
+        let password = Password::new(password);
+        let connector = Identity::new(&[IDENTITY_CONNECTOR, session_id].concat());
+        let listener = Identity::new(&[IDENTITY_LISTENER, session_id].concat());
+        let rng = rand_chacha::ChaCha20Rng::from_entropy();
+
+        let (state, message) = if is_initiator {
+            Spake2::start_a_with_rng(&password, &connector, &listener, rng)
+        } else {
+            Spake2::start_b_with_rng(&password, &connector, &listener, rng)
+        };
+        let share = message
+            .try_into()
+            .expect("SPAKE2 messages over edwards25519 are a fixed length");
+
+        Self { state, share }
+    }
+
+    /// The public share to send to the other end.
+    pub(crate) fn share(&self) -> [u8; SHARE_LENGTH] {
+        self.share
+    }
+
+    /// Finish the exchange with the other end's share, returning the shared secret. The secret will only match if both
+    /// ends used the same password.
+    pub(crate) fn finish(self, peer_share: &[u8; SHARE_LENGTH]) -> D4FTResult<Vec<u8>> {
+        self.state
+            .finish(peer_share)
+            .map_err(|_| D4FTError::InvalidKeyShare)
+    }
+}
+
+/// An ephemeral X25519 key pair, used once for a single session.
+pub(crate) struct EphemeralDh {
+    secret: x25519_dalek::EphemeralSecret,
+    public: x25519_dalek::PublicKey,
+}
+
+impl EphemeralDh {
+    pub(crate) fn generate() -> Self {
+        let secret = x25519_dalek::EphemeralSecret::random_from_rng(
+            rand_chacha::ChaCha20Rng::from_entropy(),
+        );
+        let public = x25519_dalek::PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub(crate) fn public(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    pub(crate) fn finish(self, peer_public: [u8; 32]) -> D4FTResult<[u8; 32]> {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(peer_public));
+        if !shared.was_contributory() {
+            return Err(D4FTError::InvalidKeyShare);
+        }
+        Ok(shared.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION_ID: &[u8] = b"session";
+
+    fn exchange(connector_password: &[u8], listener_password: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let connector = Pake::start(connector_password, SESSION_ID, true);
+        let listener = Pake::start(listener_password, SESSION_ID, false);
+        let (connector_share, listener_share) = (connector.share(), listener.share());
+        (
+            connector.finish(&listener_share).unwrap(),
+            listener.finish(&connector_share).unwrap(),
+        )
+    }
+
+    #[test]
+    fn same_password_agrees() {
+        let (connector_key, listener_key) = exchange(b"password", b"password");
+        assert_eq!(connector_key, listener_key);
+        assert_eq!(connector_key.len(), 32);
+    }
+
+    #[test]
+    fn wrong_password_disagrees() {
+        let (connector_key, listener_key) = exchange(b"password", b"passw0rd");
+        assert_ne!(connector_key, listener_key);
+    }
+
+    #[test]
+    fn session_id_is_bound() {
+        let connector = Pake::start(b"password", SESSION_ID, true);
+        let listener = Pake::start(b"password", b"other session", false);
+        let (connector_share, listener_share) = (connector.share(), listener.share());
+        assert_ne!(
+            connector.finish(&listener_share).unwrap(),
+            listener.finish(&connector_share).unwrap()
+        );
+    }
+
+    #[test]
+    fn shares_are_fresh() {
+        let first = Pake::start(b"password", SESSION_ID, true);
+        let second = Pake::start(b"password", SESSION_ID, true);
+        assert_ne!(first.share(), second.share());
+    }
+
+    #[test]
+    fn rejects_share_from_same_side() {
+        let connector = Pake::start(b"password", SESSION_ID, true);
+        let other_connector = Pake::start(b"password", SESSION_ID, true);
+        assert!(matches!(
+            connector.finish(&other_connector.share()),
+            Err(D4FTError::InvalidKeyShare)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_point() {
+        let listener = Pake::start(b"password", SESSION_ID, false);
+        let mut share = Pake::start(b"password", SESSION_ID, true).share();
+        // y = 2 isn't the coordinate of any point on edwards25519
+        share[1..].copy_from_slice(&[0; 32]);
+        share[1] = 2;
+        assert!(matches!(
+            listener.finish(&share),
+            Err(D4FTError::InvalidKeyShare)
+        ));
+    }
+}
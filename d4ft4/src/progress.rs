@@ -0,0 +1,85 @@
+use std::{path::PathBuf, time::Instant};
+
+use serde::Serialize;
+
+/// A snapshot of the progress of a file transfer, passed to the progress sink after every chunk.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Progress {
+    /// The path of the file currently being transferred, as it appears in the file list.
+    pub path: PathBuf,
+    pub file_bytes: u64,
+    pub file_size: u64,
+    pub total_bytes: u64,
+    pub total_size: u64,
+    /// Average throughput since the start of the transfer, not counting resumed data.
+    pub bytes_per_second: f64,
+}
+
+pub type ProgressSink = Box<dyn FnMut(&Progress) + Send>;
+
+/// Keeps track of the progress of a transfer, and reports it to the progress sink if there is one.
+#[derive(Default)]
+pub(crate) struct ProgressTracker {
+    sink: Option<ProgressSink>,
+    progress: Option<Progress>,
+    started: Option<Instant>,
+    transferred: u64,
+}
+
+impl ProgressTracker {
+    pub(crate) fn set_sink(&mut self, sink: Option<ProgressSink>) {
+        self.sink = sink;
+    }
+
+    /// Start tracking a new transfer of `total_size` bytes.
+    pub(crate) fn start(&mut self, total_size: u64) {
+        self.progress = Some(Progress {
+            path: PathBuf::new(),
+            file_bytes: 0,
+            file_size: 0,
+            total_bytes: 0,
+            total_size,
+            bytes_per_second: 0.0,
+        });
+        self.started = Some(Instant::now());
+        self.transferred = 0;
+    }
+
+    /// Start tracking a file, skipping over the first `offset` bytes that have already been transferred.
+    pub(crate) fn start_file(&mut self, path: PathBuf, size: u64, offset: u64) {
+        if let Some(progress) = &mut self.progress {
+            progress.path = path;
+            progress.file_bytes = offset;
+            progress.file_size = size;
+            progress.total_bytes += offset;
+        }
+        self.report();
+    }
+
+    pub(crate) fn advance(&mut self, bytes: u64) {
+        if let Some(progress) = &mut self.progress {
+            progress.file_bytes += bytes;
+            progress.total_bytes += bytes;
+        }
+        self.transferred += bytes;
+        self.report();
+    }
+
+    fn report(&mut self) {
+        let (Some(sink), Some(progress), Some(started)) =
+            (&mut self.sink, &mut self.progress, self.started)
+        else {
+            return;
+        };
+
+        let elapsed = started.elapsed().as_secs_f64();
+        progress.bytes_per_second = if elapsed > 0.0 {
+            self.transferred as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        sink(progress);
+    }
+}
@@ -1,15 +1,101 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
 
+/// The hash algorithm used to verify files, sent in [`FileHeader::hash`].
+pub(crate) const HASH_ALGORITHM: &str = "blake3";
+
+/// The current version of the protocol. Peers with the same major version can talk to each other, using the
+/// capabilities they have in common.
+pub(crate) const PROTOCOL_VERSION: Version = Version { major: 5, minor: 0 };
+
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct Handshake {
-    pub(crate) version: String,
+    pub(crate) version: Version,
     pub(crate) encryption: EncryptionVars,
+    /// The connecting end's PAKE share, hex encoded. Missing from peers older than version 5.
+    #[serde(default)]
+    pub(crate) key_share: Option<String>,
+    /// The connecting end's ephemeral X25519 public key, hex encoded.
+    #[serde(default)]
+    pub(crate) dh_public: Option<String>,
     pub(crate) is_sender: bool,
+    /// Whether the connecting end is a duplex peer, which can both send and receive. `is_sender` is ignored if so.
+    #[serde(default)]
+    pub(crate) duplex: bool,
+    /// Optional protocol features supported by the connecting end.
+    #[serde(default)]
+    pub(crate) capabilities: Vec<Capability>,
+    /// Compression codecs supported by the connecting end, in order of preference.
+    #[serde(default)]
+    pub(crate) compression: Vec<Compression>,
     // pub(crate) mode: TransferMode,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "VersionRepr")]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Versions are sent as `{ "major": 4, "minor": 1 }`, but older peers send a string like `"4"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VersionRepr {
+    Structured { major: u32, minor: u32 },
+    Legacy(String),
+}
+
+impl TryFrom<VersionRepr> for Version {
+    type Error = String;
+
+    fn try_from(value: VersionRepr) -> Result<Self, Self::Error> {
+        match value {
+            VersionRepr::Structured { major, minor } => Ok(Self { major, minor }),
+            VersionRepr::Legacy(version) => {
+                let (major, minor) = version.split_once('.').unwrap_or((&version, "0"));
+                Ok(Self {
+                    major: major
+                        .parse()
+                        .map_err(|_| format!("invalid version: {version}"))?,
+                    minor: minor
+                        .parse()
+                        .map_err(|_| format!("invalid version: {version}"))?,
+                })
+            }
+        }
+    }
+}
+
+/// Optional protocol features, which are only used if both ends support them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    Compression,
+    Hashing,
+    Resume,
+    Folders,
+    /// Many transfers over one connection, framed with [`SessionMessage`]s.
+    Sessions,
+    /// Duplex peers, where either end can start a transfer.
+    Duplex,
+    /// Cancelling a transfer part way through, with an [`Abort`] frame.
+    Cancel,
+    /// A capability from a newer version that we don't know about.
+    #[serde(other)]
+    Unsupported,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename = "kebab-case")]
 pub(crate) struct EncryptionVars {
@@ -21,6 +107,113 @@ pub(crate) struct EncryptionVars {
     pub(crate) server_client_nonce: String,
     #[serde(rename = "server-client-salt")]
     pub(crate) server_client_salt: String,
+    /// Missing from peers older than version 5, which always use [`Kdf::STANDARD`].
+    #[serde(default)]
+    pub(crate) kdf: Kdf,
+}
+
+/// The memory-hard KDF run on the password once per session, and its cost parameters. The connecting end picks it, and
+/// the listening end refuses anything cheaper than its minimum or more expensive than its maximum.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum Kdf {
+    #[serde(rename_all = "kebab-case")]
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    #[serde(rename_all = "kebab-case")]
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Self::STANDARD
+    }
+}
+
+impl Kdf {
+    /// The default profile, and the only one supported by peers older than version 5.
+    pub const STANDARD: Kdf = Kdf::Scrypt {
+        log_n: 16,
+        r: 8,
+        p: 1,
+    };
+    /// A cheaper profile for phones and other slow devices.
+    pub const MOBILE: Kdf = Kdf::Scrypt {
+        log_n: 14,
+        r: 8,
+        p: 1,
+    };
+    pub const ARGON2ID: Kdf = Kdf::Argon2id {
+        memory_kib: 64 * 1024,
+        iterations: 3,
+        parallelism: 1,
+    };
+    /// An expensive profile for fast machines. It is over the default
+    /// [`ConnectionOptions::max_kdf`](crate::ConnectionOptions::max_kdf), so the listening end has to raise that to
+    /// accept it.
+    pub const PARANOID: Kdf = Kdf::Argon2id {
+        memory_kib: 512 * 1024,
+        iterations: 4,
+        parallelism: 1,
+    };
+
+    /// Most memory any profile can use, even one picked locally or allowed by a raised maximum, so we can't run out.
+    const MAX_MEMORY: u64 = 1024 * 1024 * 1024 * 2;
+    /// Highest [`Kdf::cost`] of any profile, so huge iteration counts or lanes can't tie us up.
+    const MAX_COST: u64 = Self::MAX_MEMORY * 4;
+
+    /// Memory used by the KDF, in bytes.
+    fn memory(&self) -> u64 {
+        match *self {
+            Kdf::Scrypt { log_n, r, .. } if log_n < 64 => {
+                u64::try_from((128 * u128::from(r)) << log_n).unwrap_or(u64::MAX)
+            }
+            Kdf::Scrypt { .. } => u64::MAX,
+            Kdf::Argon2id { memory_kib, .. } => u64::from(memory_kib) * 1024,
+        }
+    }
+
+    /// A rough measure of how expensive the KDF is to run, as the number of bytes of memory it touches. Used to compare
+    /// profiles, including ones with different algorithms.
+    pub fn cost(&self) -> u64 {
+        match *self {
+            // Each of the p lanes fills its memory and then reads it back
+            Kdf::Scrypt { p, .. } => self.memory().saturating_mul(2 * u64::from(p)),
+            Kdf::Argon2id { iterations, .. } => self.memory().saturating_mul(u64::from(iterations)),
+        }
+    }
+
+    /// Whether this profile uses no more memory and costs no more than `max`, so a listening end can cap what a
+    /// connecting end asks it to run before it knows who it's talking to.
+    pub(crate) fn fits_within(&self, max: &Kdf) -> bool {
+        self.memory() <= max.memory() && self.cost() <= max.cost()
+    }
+
+    /// Check that the parameters are ones we are willing to run.
+    pub(crate) fn is_valid(&self) -> bool {
+        let params_ok = match *self {
+            Kdf::Scrypt { log_n, r, p } => scrypt::Params::new(log_n, r, p, 32).is_ok(),
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => argon2::Params::new(memory_kib, iterations, parallelism, Some(32)).is_ok(),
+        };
+        params_ok && self.memory() <= Self::MAX_MEMORY && self.cost() <= Self::MAX_COST
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    Zstd,
+    Lz4,
+    /// A codec from a newer version that we don't know about.
+    #[serde(other)]
+    Unsupported,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
@@ -50,6 +243,64 @@ pub(crate) enum Response {
     Reject { reason: String },
 }
 
+/// The listening end's plaintext reply to a [`Handshake`], sent before any keys are known.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "response")]
+pub(crate) enum KeyExchange {
+    Accept {
+        key_share: String,
+        dh_public: String,
+        /// The listening end's key confirmation tag, hex encoded.
+        confirmation: String,
+        /// The listening end's tag over the handshake as it was received, hex encoded.
+        handshake_tag: String,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+/// The connecting end's key confirmation and handshake tags, hex encoded. Sent even if the listening end's tags were
+/// wrong, so that both ends find out.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct KeyConfirmation {
+    pub(crate) confirmation: String,
+    pub(crate) handshake_tag: String,
+}
+
+/// The listening end's response to a [`Handshake`], with the settings it picked for the connection.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "response")]
+pub(crate) enum HandshakeResponse {
+    Accept {
+        #[serde(default)]
+        version: Option<Version>,
+        /// The capabilities supported by both ends.
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+        #[serde(default)]
+        compression: Option<Compression>,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+/// Sent by the sending end around each transfer, and when it is done with the connection, so that the receiving end knows
+/// whether another transfer is coming. Only used if both ends support [`Capability::Sessions`].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "session", rename_all = "kebab-case")]
+pub(crate) enum SessionMessage {
+    /// Sent before a transfer's [`InitTransfer`]. Transfers are numbered from 1.
+    Begin { transfer: u64 },
+    /// Sent once the receiving end has answered the transfer, whether it was accepted or not.
+    End { transfer: u64 },
+    /// No more transfers are coming, sent before closing the connection.
+    Goodbye,
+    /// Sent by a duplex peer to let the other end go ahead with the transfer it has begun.
+    Ready { transfer: u64 },
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "mode", rename_all = "lowercase")]
 pub(crate) enum InitTransfer {
@@ -83,8 +334,40 @@ impl FileListItem {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(tag = "response")]
 pub(crate) enum FileListResponse {
-    Accept { allowlist: Vec<PathBuf> },
-    Reject { reason: String },
+    Accept {
+        allowlist: Vec<PathBuf>,
+        #[serde(flatten)]
+        resume: Resume,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+/// What the receiver already has of partially received files, keyed by path.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Resume {
+    /// Offsets to resume partially received files from.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) offsets: BTreeMap<PathBuf, u64>,
+    /// The hash of everything before each offset, so the sender can check that it is resuming the same file. Files
+    /// without a hash are sent from the start.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) prefix_hashes: BTreeMap<PathBuf, String>,
+}
+
+impl Resume {
+    /// The offset to resume a file from, and the hash of everything before it.
+    pub(crate) fn get(&self, path: &Path) -> Option<(u64, &str)> {
+        let offset = *self.offsets.get(path)?;
+        let hash = self.prefix_hashes.get(path)?;
+        Some((offset, hash))
+    }
+
+    /// The offset the sender may resume a file from. The only other one it may use is 0.
+    pub(crate) fn offered(&self, path: &Path) -> u64 {
+        self.offsets.get(path).copied().unwrap_or(0)
+    }
 }
 
 // hashing should be optional
@@ -92,5 +375,112 @@ pub(crate) enum FileListResponse {
 pub(crate) struct FileHeader {
     pub(crate) path: PathBuf,
     pub(crate) size: u64,
+    /// The hash algorithm used for this file. If set, a [`FileTrailer`] is sent after the end of the file data.
     pub(crate) hash: Option<String>,
+    /// The offset in the file that the sent data starts at.
+    #[serde(default)]
+    pub(crate) offset: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct FileTrailer {
+    pub(crate) hash: String,
+}
+
+/// Sent in the frame that aborts a session, when one end cancels what it was doing. Nothing is sent after it. Only sent
+/// if both ends support [`Capability::Cancel`].
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Abort {
+    pub(crate) reason: String,
+}
+
+/// Which side of a transfer a peer is waiting to take.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerRole {
+    Sender,
+    Receiver,
+}
+
+/// Broadcast in the clear by a listening peer so that others on the local network can find it.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Beacon {
+    /// Always [`BEACON_SERVICE`], so unrelated traffic on the discovery port can be ignored.
+    pub(crate) service: String,
+    pub(crate) version: Version,
+    pub(crate) name: String,
+    pub(crate) role: PeerRole,
+    /// The port the peer is listening for connections on. The address is taken from the datagram.
+    pub(crate) port: u16,
+}
+
+pub(crate) const BEACON_SERVICE: &str = "d4ft4";
+
+/// Sent in the clear by a client when it connects to a relay, to be paired with the other client using the same ID.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct RelayRequest {
+    pub(crate) rendezvous: String,
+}
+
+/// The relay's reply to a [`RelayRequest`], sent once the other client has arrived.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "response")]
+pub(crate) enum RelayResponse {
+    /// Both clients are connected, and everything after this is forwarded between them. `listen` says which side of
+    /// the handshake this client takes: the first to arrive listens.
+    Paired {
+        listen: bool,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> serde_json::Result<Version> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn legacy_major_version() {
+        assert_eq!(parse(r#""4""#).unwrap(), Version { major: 4, minor: 0 });
+    }
+
+    #[test]
+    fn legacy_minor_version() {
+        assert_eq!(parse(r#""4.1""#).unwrap(), Version { major: 4, minor: 1 });
+    }
+
+    #[test]
+    fn structured_version() {
+        assert_eq!(
+            parse(r#"{ "major": 5, "minor": 2 }"#).unwrap(),
+            Version { major: 5, minor: 2 }
+        );
+    }
+
+    #[test]
+    fn invalid_version() {
+        for json in [r#""four""#, r#""4.x""#, r#""""#, r#""4.1.2""#] {
+            assert!(parse(json).is_err(), "{json} should not parse");
+        }
+    }
+
+    #[test]
+    fn kdf_profiles_within_cap() {
+        for kdf in [Kdf::MOBILE, Kdf::STANDARD, Kdf::ARGON2ID] {
+            assert!(kdf.fits_within(&Kdf::ARGON2ID), "{kdf:?}");
+        }
+        assert!(!Kdf::PARANOID.fits_within(&Kdf::ARGON2ID));
+        // Cheap in iterations but not in memory
+        let wide = Kdf::Argon2id {
+            memory_kib: 256 * 1024,
+            iterations: 1,
+            parallelism: 1,
+        };
+        assert!(!wide.fits_within(&Kdf::ARGON2ID));
+    }
 }
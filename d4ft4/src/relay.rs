@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+use crate::{encoding, protocol, D4FTError, D4FTResult};
+
+/// The longest rendezvous ID a relay will accept.
+pub const MAX_RENDEZVOUS_LEN: usize = 256;
+
+/// The largest relay request that will be read, which is plenty for any valid rendezvous ID.
+const MAX_REQUEST_SIZE: u64 = 1024;
+
+/// How long a client has to send its relay request after connecting.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most clients that can be waiting for the other end at once, so idle clients can't use up the relay's memory.
+const MAX_WAITING: usize = 1024;
+
+/// Clients waiting for the other client with the same rendezvous ID, each with a channel to hand that client over on.
+type Waiting = Arc<Mutex<HashMap<String, oneshot::Sender<TcpStream>>>>;
+
+/// Run a relay on `listener`, pairing up clients that connect with the same rendezvous ID and forwarding bytes between
+/// them until either end disconnects. Everything after the rendezvous is end-to-end encrypted by the clients, so the
+/// relay can't read or change what it forwards without the handshake failing. This only returns if accepting fails.
+pub async fn serve_relay(listener: TcpListener) -> D4FTResult<()> {
+    let waiting = Waiting::default();
+
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .map_err(|source| D4FTError::SocketError { source })?;
+
+        // A client that breaks the rendezvous only affects its own connection
+        tokio::spawn(rendezvous(waiting.clone(), stream));
+    }
+}
+
+async fn rendezvous(waiting: Waiting, mut stream: TcpStream) -> D4FTResult<()> {
+    let request = tokio::time::timeout(
+        REQUEST_TIMEOUT,
+        encoding::decode_plaintext::<protocol::RelayRequest, _>(&mut stream, MAX_REQUEST_SIZE),
+    )
+    .await
+    .map_err(|_| D4FTError::TimedOut)??;
+    let id = request.rendezvous;
+
+    if id.is_empty() || id.len() > MAX_RENDEZVOUS_LEN {
+        let reason = format!("rendezvous ID must be 1 to {MAX_RENDEZVOUS_LEN} bytes long");
+        return reject(stream, reason).await;
+    }
+
+    // Hand this client over to the one already waiting, if there is one and it hasn't given up
+    let receiver = loop {
+        let mut waiting = waiting.lock().expect("Relay lock should not be poisoned");
+        match waiting.remove(&id) {
+            Some(sender) => match sender.send(stream) {
+                Ok(()) => return Ok(()),
+                Err(returned) => stream = returned,
+            },
+            None => {
+                // Clients that gave up are normally cleared out, but might not have been yet
+                if waiting.len() >= MAX_WAITING {
+                    waiting.retain(|_, sender| !sender.is_closed());
+                }
+                if waiting.len() >= MAX_WAITING {
+                    break None;
+                }
+
+                let (sender, receiver) = oneshot::channel();
+                waiting.insert(id.clone(), sender);
+                break Some(receiver);
+            }
+        }
+    };
+    let Some(receiver) = receiver else {
+        return reject(stream, "too many clients are waiting".to_string()).await;
+    };
+
+    // Clients send nothing while waiting, so anything read here means this one has disconnected
+    let mut buffer = [0; 1];
+    let peer = tokio::select! {
+        peer = receiver => peer.ok(),
+        _ = stream.read(&mut buffer) => None,
+    };
+    let Some(mut peer) = peer else {
+        waiting
+            .lock()
+            .expect("Relay lock should not be poisoned")
+            .retain(|_, sender| !sender.is_closed());
+        return Err(D4FTError::ConnectionClosed);
+    };
+
+    encoding::encode_plaintext(
+        protocol::RelayResponse::Paired { listen: true },
+        &mut stream,
+    )
+    .await?;
+    encoding::encode_plaintext(protocol::RelayResponse::Paired { listen: false }, &mut peer)
+        .await?;
+
+    tokio::io::copy_bidirectional(&mut stream, &mut peer)
+        .await
+        .map_err(|source| D4FTError::SocketError { source })?;
+    Ok(())
+}
+
+/// Turn a client away, telling it why.
+async fn reject(mut stream: TcpStream, reason: String) -> D4FTResult<()> {
+    encoding::encode_plaintext(
+        protocol::RelayResponse::Reject {
+            reason: reason.clone(),
+        },
+        &mut stream,
+    )
+    .await?;
+    Err(D4FTError::RejectedHandshake { reason })
+}
@@ -0,0 +1,31 @@
+//! Test files and directories. Doesn't depend on the crate, so the unit tests inside it can share these too.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+/// An empty directory for one test to write into, named after the test binary so that binaries running at the same
+/// time don't share one.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "d4ft4-{}-{}-{name}",
+        env!("CARGO_CRATE_NAME"),
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Bytes that won't compress, from a xorshift generator.
+pub fn noise(len: usize) -> Vec<u8> {
+    let mut state = 1u32;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
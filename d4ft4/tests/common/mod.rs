@@ -0,0 +1,24 @@
+//! Helpers shared by the integration tests. Each test binary only uses some of them.
+
+#![allow(dead_code, unused_imports)]
+
+use std::path::PathBuf;
+
+use d4ft4::{D4FTResult, Receiver};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+mod files;
+
+pub use files::{noise, temp_dir};
+
+/// Receive the next file list and accept everything in it.
+pub async fn accept_all<T: AsyncRead + AsyncWrite>(
+    receiver: &mut Receiver<T>,
+) -> D4FTResult<Vec<PathBuf>> {
+    Ok(receiver
+        .receive_file_list()
+        .await?
+        .iter()
+        .map(|item| item.path().to_path_buf())
+        .collect())
+}
@@ -0,0 +1,71 @@
+//! Finding peers with beacons sent over loopback.
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use d4ft4::{DiscoveredPeer, Discovery, PeerRole};
+use futures::{Stream, StreamExt};
+
+/// Discovery on a free loopback port, so tests don't pick up real beacons or each other's.
+fn loopback_discovery() -> Discovery {
+    let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    Discovery::new()
+        .address((Ipv4Addr::LOCALHOST, port).into())
+        .interval(Duration::from_millis(50))
+}
+
+async fn next_peer(
+    peers: &mut (impl Stream<Item = d4ft4::D4FTResult<DiscoveredPeer>> + Unpin),
+) -> DiscoveredPeer {
+    tokio::time::timeout(Duration::from_secs(5), peers.next())
+        .await
+        .expect("no beacon received")
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn finds_advertised_peer() {
+    let discovery = loopback_discovery();
+    let mut peers = Box::pin(discovery.browse().await.unwrap());
+    let _advertisement = discovery
+        .advertise("laptop", PeerRole::Receiver, 4000)
+        .await
+        .unwrap();
+
+    let peer = next_peer(&mut peers).await;
+
+    assert_eq!(peer.name, "laptop");
+    assert_eq!(peer.role, PeerRole::Receiver);
+    assert_eq!(peer.address, SocketAddr::from((Ipv4Addr::LOCALHOST, 4000)));
+    // Later beacons from the same peer aren't yielded again
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), peers.next())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn finds_peer_again_after_it_expires() {
+    let discovery = loopback_discovery().expiry(Duration::from_millis(200));
+    let mut peers = Box::pin(discovery.browse().await.unwrap());
+
+    let advertisement = discovery
+        .advertise("laptop", PeerRole::Sender, 4000)
+        .await
+        .unwrap();
+    next_peer(&mut peers).await;
+    drop(advertisement);
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    let _advertisement = discovery
+        .advertise("laptop", PeerRole::Sender, 4000)
+        .await
+        .unwrap();
+    assert_eq!(next_peer(&mut peers).await.name, "laptop");
+}
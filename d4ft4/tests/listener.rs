@@ -0,0 +1,44 @@
+//! Binding before accepting, over loopback.
+
+use d4ft4::{init_receive_stream, ConnectionOptions, D4FTError, Kdf, Listener};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn connect_before_accept_on_port_zero() {
+    let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+    let addresses = listener.local_addrs().unwrap();
+    assert_eq!(addresses.len(), 1);
+    assert_ne!(addresses[0].port(), 0);
+
+    // The client connects as soon as the address is known, before the listener starts accepting
+    let stream = TcpStream::connect(addresses[0]).await.unwrap();
+    let client = tokio::spawn(async move {
+        let options = ConnectionOptions::new().kdf(Kdf::MOBILE);
+        let mut receiver = init_receive_stream(stream, false, "pw".into(), options).await?;
+        let text = receiver.receive_text().await?;
+        receiver.close().await?;
+        Ok::<_, D4FTError>(text)
+    });
+
+    let mut sender = listener
+        .accept_send("pw".into(), ConnectionOptions::new().min_kdf(Kdf::MOBILE))
+        .await
+        .unwrap();
+    sender.send_text("bound first".into()).await.unwrap();
+    sender.close().await.unwrap();
+    assert_eq!(client.await.unwrap().unwrap(), "bound first");
+}
+
+#[tokio::test]
+async fn every_address_shares_port_zero_pick() {
+    // Resolves to both 127.0.0.1 and ::1 where both are available
+    let listener = Listener::bind("localhost:0").await.unwrap();
+    let addresses = listener.local_addrs().unwrap();
+
+    let port = addresses[0].port();
+    assert_ne!(port, 0);
+    assert!(
+        addresses.iter().all(|address| address.port() == port),
+        "{addresses:?}"
+    );
+}
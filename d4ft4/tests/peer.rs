@@ -0,0 +1,212 @@
+//! Duplex peers over in-memory pipes, taking turns to send.
+
+use std::time::Duration;
+
+use d4ft4::{init_peer_stream, ConnectionOptions, D4FTError, Kdf, Peer, Transfer};
+use tokio::io::{duplex, DuplexStream};
+
+mod common;
+use common::temp_dir;
+
+/// A listening and a connecting peer, connected to each other.
+async fn peers() -> (Peer<DuplexStream>, Peer<DuplexStream>) {
+    let (a, b) = duplex(64 * 1024);
+    // A cheap KDF keeps the handshakes quick
+    let options = ConnectionOptions::new()
+        .kdf(Kdf::MOBILE)
+        .min_kdf(Kdf::MOBILE);
+
+    let (listener, connector) = tokio::join!(
+        init_peer_stream(a, true, "pw".into(), options.clone()),
+        init_peer_stream(b, false, "pw".into(), options),
+    );
+    (listener.unwrap(), connector.unwrap())
+}
+
+/// Close both peers at once, since each waits for the other's goodbye.
+async fn close(listener: Peer<DuplexStream>, connector: Peer<DuplexStream>) {
+    let (listener, connector) = tokio::join!(listener.close(), connector.close());
+    listener.unwrap();
+    connector.unwrap();
+}
+
+#[tokio::test]
+async fn both_ends_send_in_turn() {
+    let (mut listener, mut connector) = peers().await;
+    let source = temp_dir("turns-source");
+    let out_dir = temp_dir("turns-out");
+    std::fs::write(source.join("notes.txt"), b"from the connecting end").unwrap();
+
+    let (sent, received) = tokio::join!(
+        listener.send_text("from the listening end".into()),
+        connector.next_transfer(),
+    );
+    sent.unwrap();
+    assert!(
+        matches!(received.unwrap(), Some(Transfer::Text(text)) if text == "from the listening end")
+    );
+
+    let send = async {
+        let mut file = tokio::fs::File::open(source.join("notes.txt"))
+            .await
+            .unwrap();
+        connector
+            .send_flat_files(vec![("notes.txt".into(), &mut file)])
+            .await
+    };
+    let receive = async {
+        let Some(Transfer::Files(list)) = listener.next_transfer().await? else {
+            panic!("expected files");
+        };
+        let allowlist = list.iter().map(|item| item.path().to_path_buf()).collect();
+        listener
+            .receive_flat_files_fs(allowlist, Some(&out_dir))
+            .await
+    };
+    let (sent, received) = tokio::join!(send, receive);
+    sent.unwrap();
+    received.unwrap();
+    assert_eq!(
+        std::fs::read(out_dir.join("notes.txt")).unwrap(),
+        b"from the connecting end"
+    );
+
+    close(listener, connector).await;
+}
+
+#[tokio::test]
+async fn listening_end_wins_simultaneous_begin() {
+    let (mut listener, mut connector) = peers().await;
+
+    let receive_first = async {
+        let result = connector.send_text("from the connecting end".into()).await;
+        assert!(
+            matches!(result, Err(D4FTError::TransferPending)),
+            "{result:?}"
+        );
+        let transfer = connector.next_transfer().await.unwrap();
+        assert!(matches!(transfer, Some(Transfer::Text(text)) if text == "from the listening end"));
+    };
+    let (sent, ()) = tokio::join!(
+        listener.send_text("from the listening end".into()),
+        receive_first,
+    );
+    sent.unwrap();
+
+    // The connecting end's transfer was dropped, and goes through once it tries again
+    let (sent, received) = tokio::join!(
+        connector.send_text("from the connecting end".into()),
+        listener.next_transfer(),
+    );
+    sent.unwrap();
+    assert!(
+        matches!(received.unwrap(), Some(Transfer::Text(text)) if text == "from the connecting end")
+    );
+
+    close(listener, connector).await;
+}
+
+#[tokio::test]
+async fn rejects_unanswered_files() {
+    let (mut listener, mut connector) = peers().await;
+    let source = temp_dir("reject-source");
+    std::fs::write(source.join("unwanted.txt"), b"unwanted").unwrap();
+
+    let send = async {
+        let mut file = tokio::fs::File::open(source.join("unwanted.txt"))
+            .await
+            .unwrap();
+        let result = listener
+            .send_flat_files(vec![("unwanted.txt".into(), &mut file)])
+            .await;
+        assert!(
+            matches!(result, Err(D4FTError::RejectedTransfer { .. })),
+            "{result:?}"
+        );
+        listener.next_transfer().await.unwrap()
+    };
+    let skip = async {
+        let transfer = connector.next_transfer().await.unwrap();
+        assert!(matches!(transfer, Some(Transfer::Files(_))));
+        // Starting a transfer without answering the file list rejects it
+        connector.send_text("no thanks".into()).await.unwrap();
+    };
+    let (received, ()) = tokio::join!(send, skip);
+    assert!(matches!(received, Some(Transfer::Text(text)) if text == "no thanks"));
+
+    close(listener, connector).await;
+}
+
+#[tokio::test]
+async fn close_while_other_end_is_idle() {
+    let (mut listener, connector) = peers().await;
+
+    let wait = async {
+        let transfer = listener.next_transfer().await.unwrap();
+        assert!(transfer.is_none());
+        // Waiting again after the goodbye doesn't read past it
+        assert!(listener.next_transfer().await.unwrap().is_none());
+        listener.close().await
+    };
+    let (closed, waited) = tokio::join!(connector.close(), wait);
+    closed.unwrap();
+    waited.unwrap();
+}
+
+#[tokio::test]
+async fn close_with_transfer_pending() {
+    let (mut listener, mut connector) = peers().await;
+
+    let send = async {
+        let result = listener.send_text("never received".into()).await;
+        assert!(
+            matches!(result, Err(D4FTError::ConnectionClosed)),
+            "{result:?}"
+        );
+        // Nothing more can be sent once the other end has said goodbye
+        let result = listener.send_text("too late".into()).await;
+        assert!(
+            matches!(result, Err(D4FTError::ConnectionClosed)),
+            "{result:?}"
+        );
+        listener.close().await
+    };
+    let give_up = async {
+        let result = connector.send_text("also never received".into()).await;
+        assert!(
+            matches!(result, Err(D4FTError::TransferPending)),
+            "{result:?}"
+        );
+        // Close without receiving the listening end's transfer
+        connector.close().await
+    };
+    let (sent, closed) = tokio::join!(send, give_up);
+    sent.unwrap();
+    closed.unwrap();
+}
+
+#[tokio::test]
+async fn cancel_while_waiting_to_send() {
+    let (mut listener, mut connector) = peers().await;
+    let cancel = connector.cancel_token();
+
+    // The listening end isn't waiting for a transfer yet, so the connecting end is stuck until it gives up
+    let send = async {
+        let result = connector.send_text("never received".into()).await;
+        assert!(
+            matches!(&result, Err(D4FTError::Cancelled { reason }) if reason == "gave up"),
+            "{result:?}"
+        );
+    };
+    let give_up = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cancel.cancel("gave up");
+    };
+    tokio::join!(send, give_up);
+
+    let result = listener.next_transfer().await;
+    assert!(
+        matches!(&result, Err(D4FTError::Cancelled { reason }) if reason == "gave up"),
+        "{result:?}"
+    );
+}
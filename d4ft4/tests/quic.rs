@@ -0,0 +1,143 @@
+//! QUIC transfers on loopback.
+
+#![cfg(feature = "quic")]
+
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use d4ft4::{init_receive_quic, init_send_quic, D4FTError, D4FTResult, QuicReceiver};
+
+mod common;
+use common::temp_dir;
+
+/// Find a free loopback port for the listening end, so tests don't collide with each other or anything else.
+fn free_address() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Wait until something is bound to `address`, so the connecting end's first packets aren't lost.
+async fn wait_until_bound(address: SocketAddr) {
+    while UdpSocket::bind(address).is_ok() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Write `count` files of a few megabytes each, with different contents, returning their paths.
+fn write_files(dir: &Path, prefix: &str, count: u8) -> Vec<PathBuf> {
+    (0..count)
+        .map(|i| {
+            let path = dir.join(format!("{prefix}{i}.bin"));
+            let data = (0..3_000_000u32)
+                .map(|x| (x as u8).wrapping_mul(i + 1))
+                .collect::<Vec<_>>();
+            std::fs::write(&path, data).unwrap();
+            path
+        })
+        .collect()
+}
+
+async fn receive_all(receiver: &mut QuicReceiver, out_dir: &Path) -> D4FTResult<()> {
+    let allowlist = receiver
+        .receive_file_list()
+        .await?
+        .iter()
+        .map(|item| item.path().to_path_buf())
+        .collect();
+    receiver.receive_files_fs(allowlist, Some(out_dir)).await
+}
+
+#[tokio::test]
+async fn parallel_files_over_several_transfers() {
+    let source = temp_dir("source");
+    let out_dir = temp_dir("out");
+    // More files than are sent at once, so that streams are in flight together and queue up
+    let first = write_files(&source, "first", 6);
+    let second = write_files(&source, "second", 3);
+    let address = free_address();
+
+    let receive = tokio::spawn({
+        let out_dir = out_dir.clone();
+        async move {
+            let mut receiver =
+                init_receive_quic(true, address, "pw".into(), Default::default()).await?;
+            receive_all(&mut receiver, &out_dir).await?;
+            // The second transfer's streams must be keyed apart from the first's
+            let text = receiver.receive_text().await?;
+            receive_all(&mut receiver, &out_dir).await?;
+            receiver.close().await?;
+            Ok::<_, D4FTError>(text)
+        }
+    });
+    wait_until_bound(address).await;
+
+    let mut sender = init_send_quic(false, address, "pw".into(), Default::default())
+        .await
+        .unwrap();
+    sender.send_files(&first).await.unwrap();
+    sender.send_text("between transfers".into()).await.unwrap();
+    sender.send_files(&second).await.unwrap();
+    sender.close().await.unwrap();
+
+    assert_eq!(receive.await.unwrap().unwrap(), "between transfers");
+    for path in first.iter().chain(&second) {
+        let received = out_dir.join(path.file_name().unwrap());
+        assert_eq!(
+            std::fs::read(path).unwrap(),
+            std::fs::read(received).unwrap()
+        );
+    }
+}
+
+#[tokio::test]
+async fn rejects_duplicate_names() {
+    let source = temp_dir("duplicate-source");
+    let out_dir = temp_dir("duplicate-out");
+    std::fs::create_dir(source.join("a")).unwrap();
+    std::fs::create_dir(source.join("b")).unwrap();
+    let duplicates = [source.join("a/x.bin"), source.join("b/x.bin")];
+    for path in &duplicates {
+        std::fs::write(path, b"same name").unwrap();
+    }
+    let files = write_files(&source, "after", 2);
+    let address = free_address();
+
+    let receive = tokio::spawn({
+        let out_dir = out_dir.clone();
+        async move {
+            let mut receiver =
+                init_receive_quic(true, address, "pw".into(), Default::default()).await?;
+            let text = receiver.receive_text().await?;
+            receive_all(&mut receiver, &out_dir).await?;
+            receiver.close().await?;
+            Ok::<_, D4FTError>(text)
+        }
+    });
+    wait_until_bound(address).await;
+
+    let mut sender = init_send_quic(false, address, "pw".into(), Default::default())
+        .await
+        .unwrap();
+    let sent = sender.send_files(&duplicates).await;
+    assert!(
+        matches!(&sent, Err(D4FTError::DuplicateFileName { name }) if name == Path::new("x.bin")),
+        "{sent:?}"
+    );
+    // Nothing was offered, so both ends still agree on which streams come next
+    sender.send_text("still connected".into()).await.unwrap();
+    sender.send_files(&files).await.unwrap();
+    sender.close().await.unwrap();
+
+    assert_eq!(receive.await.unwrap().unwrap(), "still connected");
+    for path in &files {
+        let received = out_dir.join(path.file_name().unwrap());
+        assert_eq!(
+            std::fs::read(path).unwrap(),
+            std::fs::read(received).unwrap()
+        );
+    }
+    assert!(!out_dir.join("x.bin").exists());
+}
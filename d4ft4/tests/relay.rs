@@ -0,0 +1,63 @@
+//! Transfers through a relay running in the same process.
+
+use std::net::SocketAddr;
+
+use d4ft4::{init_receive_relay, init_send_relay, serve_relay, ConnectionOptions, D4FTError};
+use tokio::net::TcpListener;
+
+mod common;
+use common::{accept_all, temp_dir};
+
+async fn start_relay() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve_relay(listener));
+    address
+}
+
+#[tokio::test]
+async fn transfer_through_relay() {
+    let relay = start_relay().await;
+    let dir = temp_dir("transfer");
+    std::fs::create_dir(dir.join("out")).unwrap();
+    std::fs::write(dir.join("file.txt"), b"relayed file").unwrap();
+
+    let receive = async {
+        let mut receiver =
+            init_receive_relay(relay, "room", "pw".into(), ConnectionOptions::new()).await?;
+        let text = receiver.receive_text().await?;
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver
+            .receive_flat_files_fs(allowlist, Some(&dir.join("out")))
+            .await?;
+        receiver.close().await?;
+        Ok::<_, D4FTError>(text)
+    };
+    let send = async {
+        let mut sender =
+            init_send_relay(relay, "room", "pw".into(), ConnectionOptions::new()).await?;
+        sender.send_text("hello through the relay".into()).await?;
+        let mut file = tokio::fs::File::open(dir.join("file.txt")).await.unwrap();
+        sender
+            .send_flat_files(vec![(dir.join("file.txt"), &mut file)])
+            .await?;
+        sender.close().await
+    };
+
+    let (text, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    assert_eq!(text.unwrap(), "hello through the relay");
+    assert_eq!(
+        std::fs::read(dir.join("out").join("file.txt")).unwrap(),
+        b"relayed file"
+    );
+}
+
+#[tokio::test]
+async fn rejects_long_rendezvous_id() {
+    let relay = start_relay().await;
+
+    let too_long = "x".repeat(d4ft4::MAX_RENDEZVOUS_LEN + 1);
+    let result = init_send_relay(relay, &too_long, "pw".into(), ConnectionOptions::new()).await;
+    assert!(matches!(result, Err(D4FTError::RejectedHandshake { .. })));
+}
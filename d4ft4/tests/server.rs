@@ -0,0 +1,85 @@
+//! A server handling several clients over loopback.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use d4ft4::{init_receive_with_options, ConnectionOptions, D4FTError, Kdf, Server};
+use futures::StreamExt;
+use tokio::net::TcpStream;
+
+/// Far too cheap for real use, but it keeps handshakes well within the timeouts here.
+const FAST_KDF: Kdf = Kdf::Scrypt {
+    log_n: 10,
+    r: 8,
+    p: 1,
+};
+
+/// Start a server on a free loopback port that allows [`FAST_KDF`], returning it and its address.
+async fn start_server() -> (Server, SocketAddr) {
+    let server = Server::bind("127.0.0.1:0", "pw".into())
+        .await
+        .unwrap()
+        .options(ConnectionOptions::new().min_kdf(FAST_KDF));
+    let address = server.local_addrs().unwrap()[0];
+    (server, address)
+}
+
+/// Connect to the server as a receiver, and return the text it sends.
+async fn receive_text(address: SocketAddr) -> Result<String, D4FTError> {
+    let mut receiver = init_receive_with_options(
+        false,
+        address,
+        "pw".into(),
+        ConnectionOptions::new().kdf(FAST_KDF),
+    )
+    .await?;
+    let text = receiver.receive_text().await?;
+    receiver.close().await?;
+    Ok(text)
+}
+
+#[tokio::test]
+async fn silent_client_times_out_and_frees_its_place() {
+    let (server, address) = start_server().await;
+    let mut senders = server
+        .max_concurrent(1)
+        .handshake_timeout(Duration::from_millis(500))
+        .senders();
+
+    // Connects, but never starts the handshake
+    let _silent = TcpStream::connect(address).await.unwrap();
+    let client = tokio::spawn(receive_text(address));
+
+    assert!(matches!(
+        senders.next().await.unwrap(),
+        Err(D4FTError::TimedOut)
+    ));
+    let mut sender = senders.next().await.unwrap().unwrap();
+    sender.send_text("after the timeout".into()).await.unwrap();
+    sender.close().await.unwrap();
+    assert_eq!(client.await.unwrap().unwrap(), "after the timeout");
+}
+
+#[tokio::test]
+async fn serves_more_clients_than_the_limit() {
+    const LIMIT: usize = 2;
+    let (server, address) = start_server().await;
+    let mut senders = server.max_concurrent(LIMIT).senders();
+
+    // The last client waits until one of the others has been taken from the stream
+    let clients = (0..=LIMIT)
+        .map(|_| tokio::spawn(receive_text(address)))
+        .collect::<Vec<_>>();
+    for i in 0..=LIMIT {
+        let mut sender = senders.next().await.unwrap().unwrap();
+        sender.send_text(format!("client {i}")).await.unwrap();
+        sender.close().await.unwrap();
+    }
+
+    let mut texts = Vec::new();
+    for client in clients {
+        texts.push(client.await.unwrap().unwrap());
+    }
+    texts.sort();
+    assert_eq!(texts, ["client 0", "client 1", "client 2"]);
+}
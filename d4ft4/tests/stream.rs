@@ -0,0 +1,634 @@
+//! Round trips over in-memory pipes, checking that the handshake and session framing catch the ways a stream can go
+//! wrong.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use d4ft4::{
+    init_receive_stream, init_send_stream, ConnectionOptions, D4FTError, D4FTResult, Kdf, Progress,
+    ProgressSink, Transfer,
+};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+mod common;
+use common::{accept_all, noise, temp_dir};
+
+const PIPE_SIZE: usize = 64 * 1024;
+
+#[tokio::test]
+async fn text_round_trip() {
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let text = receiver.receive_text().await?;
+        receiver.close().await?;
+        Ok::<_, D4FTError>(text)
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        sender.send_text("over a pipe".into()).await?;
+        sender.close().await
+    };
+
+    let (text, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    assert_eq!(text.unwrap(), "over a pipe");
+}
+
+#[tokio::test]
+async fn files_round_trip() {
+    let source = temp_dir("files-source");
+    let out_dir = temp_dir("files-out");
+    let big = (0..5_000_000u32)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    std::fs::write(source.join("big.bin"), &big).unwrap();
+    std::fs::write(source.join("small.txt"), b"hello").unwrap();
+
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver
+            .receive_flat_files_fs(allowlist, Some(&out_dir))
+            .await?;
+        receiver.close().await
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        let mut big = tokio::fs::File::open(source.join("big.bin")).await.unwrap();
+        let mut small = tokio::fs::File::open(source.join("small.txt"))
+            .await
+            .unwrap();
+        sender
+            .send_flat_files(vec![
+                (source.join("big.bin"), &mut big),
+                (source.join("small.txt"), &mut small),
+            ])
+            .await?;
+        sender.close().await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    received.unwrap();
+    assert_eq!(std::fs::read(out_dir.join("big.bin")).unwrap(), big);
+    assert_eq!(std::fs::read(out_dir.join("small.txt")).unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn session_of_transfers() {
+    let source = temp_dir("session-source");
+    let out_dir = temp_dir("session-out");
+    std::fs::write(source.join("one.txt"), b"one").unwrap();
+    std::fs::write(source.join("two.txt"), b"two").unwrap();
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let mut transfers = Vec::new();
+        while let Some(transfer) = receiver.next_transfer().await? {
+            if let Transfer::Files(list) = &transfer {
+                let allowlist = list.iter().map(|item| item.path().to_path_buf()).collect();
+                receiver
+                    .receive_flat_files_fs(allowlist, Some(&out_dir))
+                    .await?;
+            }
+            transfers.push(transfer);
+        }
+        receiver.close().await?;
+        Ok::<_, D4FTError>(transfers)
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        sender.send_text("before".into()).await?;
+        let mut one = tokio::fs::File::open(source.join("one.txt")).await.unwrap();
+        let mut two = tokio::fs::File::open(source.join("two.txt")).await.unwrap();
+        sender
+            .send_flat_files(vec![
+                (source.join("one.txt"), &mut one),
+                (source.join("two.txt"), &mut two),
+            ])
+            .await?;
+        sender.send_text("after".into()).await?;
+        sender.close().await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    let transfers = received.unwrap();
+    assert_eq!(transfers.len(), 3, "{transfers:?}");
+    assert_eq!(transfers[0], Transfer::Text("before".into()));
+    assert!(
+        matches!(&transfers[1], Transfer::Files(list) if list.len() == 2),
+        "{transfers:?}"
+    );
+    assert_eq!(transfers[2], Transfer::Text("after".into()));
+    assert_eq!(std::fs::read(out_dir.join("one.txt")).unwrap(), b"one");
+    assert_eq!(std::fs::read(out_dir.join("two.txt")).unwrap(), b"two");
+}
+
+/// A progress sink that records every report it gets.
+fn recording_sink() -> (ProgressSink, Arc<Mutex<Vec<Progress>>>) {
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let sink = {
+        let reports = reports.clone();
+        Box::new(move |progress: &Progress| reports.lock().unwrap().push(progress.clone()))
+    };
+    (sink, reports)
+}
+
+/// Check that progress for `files`, given as their names and sizes, was reported for each of them in turn, only ever
+/// went up, and ended with everything transferred.
+fn check_progress(reports: &[Progress], files: &[(&str, u64)]) {
+    let total = files.iter().map(|(_, size)| size).sum::<u64>();
+    assert!(reports.iter().all(|progress| progress.total_size == total));
+    for pair in reports.windows(2) {
+        assert!(pair[1].total_bytes >= pair[0].total_bytes, "{pair:?}");
+        if pair[1].path == pair[0].path {
+            assert!(pair[1].file_bytes >= pair[0].file_bytes, "{pair:?}");
+        }
+    }
+
+    let mut reported = reports
+        .iter()
+        .map(|progress| progress.path.clone())
+        .collect::<Vec<_>>();
+    reported.dedup();
+    let names = files
+        .iter()
+        .map(|(name, _)| PathBuf::from(name))
+        .collect::<Vec<_>>();
+    assert_eq!(reported, names);
+    for (name, size) in files {
+        let last = reports
+            .iter()
+            .rev()
+            .find(|progress| progress.path == Path::new(name))
+            .unwrap();
+        assert_eq!(last.file_bytes, *size);
+        assert_eq!(last.file_size, *size);
+    }
+    assert_eq!(reports.last().unwrap().total_bytes, total);
+}
+
+#[tokio::test]
+async fn progress_reports() {
+    let source = temp_dir("progress-source");
+    let out_dir = temp_dir("progress-out");
+    // Several chunks, so the big file is reported more than once
+    std::fs::write(source.join("big.bin"), vec![1u8; 9_000_000]).unwrap();
+    std::fs::write(source.join("small.txt"), b"hello").unwrap();
+    let (send_sink, sent_reports) = recording_sink();
+    let (receive_sink, received_reports) = recording_sink();
+
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        receiver.set_progress_sink(Some(receive_sink));
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver
+            .receive_flat_files_fs(allowlist, Some(&out_dir))
+            .await?;
+        receiver.close().await
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        sender.set_progress_sink(Some(send_sink));
+        let mut big = tokio::fs::File::open(source.join("big.bin")).await.unwrap();
+        let mut small = tokio::fs::File::open(source.join("small.txt"))
+            .await
+            .unwrap();
+        sender
+            .send_flat_files(vec![
+                (source.join("big.bin"), &mut big),
+                (source.join("small.txt"), &mut small),
+            ])
+            .await?;
+        sender.close().await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    received.unwrap();
+    let files = [("big.bin", 9_000_000), ("small.txt", 5)];
+    let sent_reports = sent_reports.lock().unwrap();
+    assert!(sent_reports.len() > 3, "{}", sent_reports.len());
+    check_progress(&sent_reports, &files);
+    check_progress(&received_reports.lock().unwrap(), &files);
+}
+
+#[tokio::test]
+async fn tree_round_trip() {
+    let source = temp_dir("tree-source");
+    let out_dir = temp_dir("tree-out");
+    let root = source.join("photos");
+    std::fs::create_dir_all(root.join("2024/summer")).unwrap();
+    std::fs::create_dir_all(root.join("empty")).unwrap();
+    std::fs::write(root.join("cover.jpg"), b"cover").unwrap();
+    std::fs::write(root.join("2024/summer/beach.jpg"), b"beach").unwrap();
+
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver.receive_tree_fs(allowlist, Some(&out_dir)).await?;
+        receiver.close().await
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        sender.send_tree(&[&root]).await?;
+        sender.close().await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    received.unwrap();
+    let out = out_dir.join("photos");
+    assert_eq!(std::fs::read(out.join("cover.jpg")).unwrap(), b"cover");
+    assert_eq!(
+        std::fs::read(out.join("2024/summer/beach.jpg")).unwrap(),
+        b"beach"
+    );
+    assert!(out.join("empty").is_dir());
+    assert_eq!(std::fs::read_dir(out.join("empty")).unwrap().count(), 0);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn tree_skips_special_files() {
+    let source = temp_dir("special-source");
+    let out_dir = temp_dir("special-out");
+    let root = source.join("r");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("file.txt"), b"file").unwrap();
+    let status = std::process::Command::new("mkfifo")
+        .arg(root.join("pipe"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let list = receiver.receive_file_list().await?;
+        let allowlist = list.iter().map(|item| item.path().to_path_buf()).collect();
+        receiver.receive_tree_fs(allowlist, Some(&out_dir)).await?;
+        receiver.close().await?;
+        Ok::<_, D4FTError>(list)
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        sender.send_tree(&[&root]).await?;
+        sender.close().await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    let mut paths = received
+        .unwrap()
+        .iter()
+        .map(|item| item.path().to_path_buf())
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, [PathBuf::from("r"), PathBuf::from("r/file.txt")]);
+    assert!(!out_dir.join("r/pipe").exists());
+    assert!(!out_dir.join("r/socket").exists());
+}
+
+/// Send `file` into `out_dir`, returning the offset the sender started sending it from.
+async fn send_file_into(file: &Path, out_dir: &Path) -> u64 {
+    let (a, b) = duplex(PIPE_SIZE);
+    let started_at = Arc::new(Mutex::new(None));
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver
+            .receive_flat_files_fs(allowlist, Some(out_dir))
+            .await?;
+        receiver.close().await
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        let started = started_at.clone();
+        sender.set_progress_sink(Some(Box::new(move |progress| {
+            started.lock().unwrap().get_or_insert(progress.file_bytes);
+        })));
+        let mut handle = tokio::fs::File::open(file).await.unwrap();
+        sender
+            .send_flat_files(vec![(file.to_path_buf(), &mut handle)])
+            .await?;
+        sender.close().await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    received.unwrap();
+    let offset = started_at
+        .lock()
+        .unwrap()
+        .expect("progress should be reported");
+    offset
+}
+
+#[tokio::test]
+async fn resume_partial_file() {
+    let source = temp_dir("resume-source");
+    let out_dir = temp_dir("resume-out");
+    let data = (0..1_000_000u32)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    std::fs::write(source.join("data.bin"), &data).unwrap();
+    // Left over from a transfer that was cut off
+    std::fs::write(out_dir.join("data.bin.d4ft4-partial"), &data[..400_000]).unwrap();
+
+    let offset = send_file_into(&source.join("data.bin"), &out_dir).await;
+
+    assert_eq!(offset, 400_000);
+    assert_eq!(std::fs::read(out_dir.join("data.bin")).unwrap(), data);
+    assert!(!out_dir.join("data.bin.d4ft4-partial").exists());
+}
+
+#[tokio::test]
+async fn restart_mismatched_partial_file() {
+    let source = temp_dir("restart-source");
+    let out_dir = temp_dir("restart-out");
+    let data = (0..1_000_000u32)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    std::fs::write(source.join("data.bin"), &data).unwrap();
+    // A partial file of something else with the same name, which must not end up at the start of this one
+    std::fs::write(
+        out_dir.join("data.bin.d4ft4-partial"),
+        vec![0xffu8; 400_000],
+    )
+    .unwrap();
+
+    let offset = send_file_into(&source.join("data.bin"), &out_dir).await;
+
+    assert_eq!(offset, 0);
+    assert_eq!(std::fs::read(out_dir.join("data.bin")).unwrap(), data);
+    assert!(!out_dir.join("data.bin.d4ft4-partial").exists());
+}
+
+#[tokio::test]
+async fn cancel_mid_file() {
+    let source = temp_dir("cancel-source");
+    let out_dir = temp_dir("cancel-out");
+    // Doesn't compress, so the sender has to wait for the receiver to read each chunk and can notice the cancel
+    std::fs::write(source.join("big.bin"), noise(8_000_000)).unwrap();
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver
+            .receive_flat_files_fs(allowlist, Some(&out_dir))
+            .await
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        let cancel = sender.cancel_token();
+        // Cancel as soon as some of the file has been sent, the way a cancel button would part way through
+        sender.set_progress_sink(Some(Box::new(move |progress| {
+            if progress.file_bytes > 0 {
+                cancel.cancel("stopped by the user");
+            }
+        })));
+        let mut handle = tokio::fs::File::open(source.join("big.bin")).await.unwrap();
+        sender
+            .send_flat_files(vec![("big.bin".into(), &mut handle)])
+            .await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    assert!(
+        matches!(&sent, Err(D4FTError::Cancelled { reason }) if reason == "stopped by the user"),
+        "{sent:?}"
+    );
+    assert!(
+        matches!(&received, Err(D4FTError::Cancelled { reason }) if reason == "stopped by the user"),
+        "{received:?}"
+    );
+    assert!(!out_dir.join("big.bin").exists());
+}
+
+#[tokio::test]
+async fn receiver_cancels_mid_file() {
+    let source = temp_dir("receiver-cancel-source");
+    let out_dir = temp_dir("receiver-cancel-out");
+    std::fs::write(source.join("big.bin"), noise(8_000_000)).unwrap();
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let cancel = receiver.cancel_token();
+        receiver.set_progress_sink(Some(Box::new(move |progress| {
+            if progress.file_bytes > 0 {
+                cancel.cancel("stopped by the receiver");
+            }
+        })));
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver
+            .receive_flat_files_fs(allowlist, Some(&out_dir))
+            .await
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        let mut handle = tokio::fs::File::open(source.join("big.bin")).await.unwrap();
+        sender
+            .send_flat_files(vec![("big.bin".into(), &mut handle)])
+            .await
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    assert!(
+        matches!(&received, Err(D4FTError::Cancelled { reason }) if reason == "stopped by the receiver"),
+        "{received:?}"
+    );
+    assert!(
+        matches!(&sent, Err(D4FTError::Cancelled { reason }) if reason == "stopped by the receiver"),
+        "{sent:?}"
+    );
+    assert!(!out_dir.join("big.bin").exists());
+}
+
+#[tokio::test]
+async fn wrong_password() {
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let (received, sent) = tokio::join!(
+        init_receive_stream(a, true, "right".into(), Default::default()),
+        init_send_stream(b, false, "wrong".into(), Default::default()),
+    );
+
+    assert!(matches!(received, Err(D4FTError::WrongPassword)));
+    assert!(matches!(sent, Err(D4FTError::WrongPassword)));
+}
+
+/// Handshake with the connecting end using `kdf` and the listening end using `listen_options`.
+async fn handshake_with_kdf(
+    kdf: Kdf,
+    listen_options: ConnectionOptions,
+) -> (D4FTResult<()>, D4FTResult<()>) {
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let (received, sent) = tokio::join!(
+        init_receive_stream(a, true, "pw".into(), listen_options),
+        init_send_stream(b, false, "pw".into(), ConnectionOptions::new().kdf(kdf)),
+    );
+    (received.map(|_| ()), sent.map(|_| ()))
+}
+
+#[tokio::test]
+async fn rejects_kdf_below_minimum() {
+    // Listeners only take the cheaper mobile profile if they opt in to it
+    let (received, sent) = handshake_with_kdf(Kdf::MOBILE, Default::default()).await;
+
+    assert!(matches!(received, Err(D4FTError::RejectedHandshake { .. })));
+    assert!(matches!(sent, Err(D4FTError::RejectedHandshake { .. })));
+}
+
+#[tokio::test]
+async fn accepts_lowered_minimum_kdf() {
+    let options = ConnectionOptions::new().min_kdf(Kdf::MOBILE);
+    let (received, sent) = handshake_with_kdf(Kdf::MOBILE, options).await;
+
+    received.unwrap();
+    sent.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_kdf_above_maximum() {
+    let options = ConnectionOptions::new().max_kdf(Kdf::MOBILE);
+    let (received, sent) = handshake_with_kdf(Kdf::STANDARD, options).await;
+
+    assert!(matches!(received, Err(D4FTError::RejectedHandshake { .. })));
+    assert!(matches!(sent, Err(D4FTError::RejectedHandshake { .. })));
+}
+
+#[tokio::test]
+async fn tampered_handshake() {
+    let (listener, listener_end) = duplex(PIPE_SIZE);
+    let (connector, connector_end) = duplex(PIPE_SIZE);
+
+    // Swap the connecting end's compression preferences in transit, which doesn't change the length of the handshake
+    let tamper = async move {
+        let (mut from_connector, mut to_connector) = tokio::io::split(connector_end);
+        let (mut from_listener, mut to_listener) = tokio::io::split(listener_end);
+
+        let mut header = [0u8; 12];
+        from_connector.read_exact(&mut header).await.unwrap();
+        let len = u64::from_be_bytes(header[4..].try_into().unwrap());
+        let mut body = vec![0u8; len as usize];
+        from_connector.read_exact(&mut body).await.unwrap();
+
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains(r#""zstd","lz4""#));
+        let body = body.replace(r#""zstd","lz4""#, r#""lz4","zstd""#);
+        to_listener.write_all(&header).await.unwrap();
+        to_listener.write_all(body.as_bytes()).await.unwrap();
+
+        let _ = tokio::join!(
+            tokio::io::copy(&mut from_connector, &mut to_listener),
+            tokio::io::copy(&mut from_listener, &mut to_connector),
+        );
+    };
+    tokio::spawn(tamper);
+
+    let (received, sent) = tokio::join!(
+        init_receive_stream(listener, true, "pw".into(), Default::default()),
+        init_send_stream(connector, false, "pw".into(), Default::default()),
+    );
+
+    assert!(matches!(received, Err(D4FTError::TamperedHandshake)));
+    assert!(matches!(sent, Err(D4FTError::TamperedHandshake)));
+}
+
+#[tokio::test]
+async fn truncated_session() {
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let mut receiver = init_receive_stream(a, true, "pw".into(), Default::default()).await?;
+        let first = receiver.receive_text().await?;
+        Ok::<_, D4FTError>((first, receiver.receive_text().await))
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        sender.send_text("first".into()).await?;
+        // Hang up without closing the session
+        drop(sender);
+        Ok::<_, D4FTError>(())
+    };
+
+    let (received, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    let (first, second) = received.unwrap();
+    assert_eq!(first, "first");
+    assert!(matches!(second, Err(D4FTError::Truncated)), "{second:?}");
+}
+
+#[tokio::test]
+async fn oversized_header() {
+    let (a, mut b) = duplex(PIPE_SIZE);
+
+    let options = ConnectionOptions::new().max_control_size(1024);
+    let receive = init_receive_stream(a, true, "pw".into(), options);
+    let send = async move {
+        // Claim a message far bigger than the limit, which must be refused before anything is allocated for it
+        let mut header = b"D4FT".to_vec();
+        header.extend_from_slice(&u64::MAX.to_be_bytes());
+        b.write_all(&header).await.unwrap();
+        b
+    };
+
+    let (received, _b) = tokio::join!(receive, send);
+    assert!(matches!(
+        received,
+        Err(D4FTError::MessageTooLarge {
+            size: u64::MAX,
+            limit: 1024
+        })
+    ));
+}
+
+#[tokio::test]
+async fn oversized_chunk() {
+    let source = temp_dir("oversized-source");
+    let out_dir = temp_dir("oversized-out");
+    std::fs::write(source.join("big.bin"), noise(100_000)).unwrap();
+    let (a, b) = duplex(PIPE_SIZE);
+
+    let receive = async {
+        let options = ConnectionOptions::new().max_chunk_size(1024);
+        let mut receiver = init_receive_stream(a, true, "pw".into(), options).await?;
+        let allowlist = accept_all(&mut receiver).await?;
+        receiver
+            .receive_flat_files_fs(allowlist, Some(&out_dir))
+            .await
+    };
+    let send = async {
+        let mut sender = init_send_stream(b, false, "pw".into(), Default::default()).await?;
+        let mut handle = tokio::fs::File::open(source.join("big.bin")).await.unwrap();
+        sender
+            .send_flat_files(vec![("big.bin".into(), &mut handle)])
+            .await
+    };
+
+    // The sender may or may not finish writing before the receiver hangs up, so only the receiver is checked
+    let (received, _sent) = tokio::join!(receive, send);
+    // The whole file is one chunk, refused from the length in its header before the rest of the frame is read
+    assert!(
+        matches!(received, Err(D4FTError::MessageTooLarge { size, limit }) if limit < 2048 && size > 100_000),
+        "{received:?}"
+    );
+    assert!(!out_dir.join("big.bin").exists());
+}
@@ -0,0 +1,64 @@
+//! Transfers over Unix domain sockets.
+#![cfg(unix)]
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use d4ft4::{init_receive_unix, init_send_unix, ConnectionOptions, D4FTError};
+
+mod common;
+use common::temp_dir;
+
+/// A path for one test's socket, with nothing at it.
+fn socket_path(name: &str) -> PathBuf {
+    temp_dir(name).join("d4ft4.sock")
+}
+
+/// Leave a socket file at `path` that nothing is listening on, like one from a listener that crashed.
+fn leave_stale_socket(path: &Path) {
+    drop(std::os::unix::net::UnixListener::bind(path).unwrap());
+    assert!(path.exists());
+}
+
+#[tokio::test]
+async fn listen_over_stale_socket() {
+    let path = socket_path("stale");
+    leave_stale_socket(&path);
+
+    let receive = async {
+        let mut receiver =
+            init_receive_unix(true, &path, "pw".into(), ConnectionOptions::new()).await?;
+        let text = receiver.receive_text().await?;
+        receiver.close().await?;
+        Ok::<_, D4FTError>(text)
+    };
+    let send = async {
+        // The listener may not have replaced the stale socket yet
+        let mut sender = loop {
+            match init_send_unix(false, &path, "pw".into(), ConnectionOptions::new()).await {
+                Err(D4FTError::SocketError { .. }) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                result => break result?,
+            }
+        };
+        sender.send_text("over a socket".into()).await?;
+        sender.close().await
+    };
+
+    let (text, sent) = tokio::join!(receive, send);
+    sent.unwrap();
+    assert_eq!(text.unwrap(), "over a socket");
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn live_socket_is_left_alone() {
+    let path = socket_path("live");
+    let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+    let result = init_receive_unix(true, &path, "pw".into(), ConnectionOptions::new()).await;
+
+    assert!(matches!(result, Err(D4FTError::SocketError { .. })));
+    assert!(path.exists());
+}